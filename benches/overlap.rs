@@ -0,0 +1,80 @@
+use bio::data_structures::interval_tree::IntervalTree;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+
+use infer_experiment::{annotation::FeatureStrand, overlap::IntervalIndex};
+
+/// 模拟GENCODE规模的注释：25条contig，约6万个基因、160万个exon
+const CONTIGS: usize = 25;
+const GENES_PER_CONTIG: usize = 2400;
+const CONTIG_LEN: u64 = 120_000_000;
+/// 每次迭代查询的read数量
+const READS: usize = 1_000_000;
+const READ_LEN: u64 = 150;
+
+/// 固定种子的线性同余随机数，每次运行得到相同的数据
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+/// 每条contig上的exon，基因随机分布（会互相重叠），每个基因约27个exon，exon长度50-350bp
+fn exons(rng: &mut Lcg) -> Vec<Vec<(u64, u64, FeatureStrand)>> {
+    (0..CONTIGS).map(|_| {
+        let mut v: Vec<(u64, u64, FeatureStrand)> = Vec::new();
+        for _ in 0..GENES_PER_CONTIG {
+            let strand = if rng.next(2) == 0 { FeatureStrand::Forward } else { FeatureStrand::Reverse };
+            let mut pos = rng.next(CONTIG_LEN);
+            for _ in 0..(2 + rng.next(50)) {
+                let len = 50 + rng.next(300);
+                v.push((pos, pos + len, strand));
+                pos += len + 100 + rng.next(5000);
+            }
+        }
+        v
+    }).collect()
+}
+
+/// read的(contig, start)，一半落在exon上，一半随机分布
+fn reads(rng: &mut Lcg, exons: &[Vec<(u64, u64, FeatureStrand)>]) -> Vec<(usize, u64)> {
+    (0..READS).map(|i| {
+        let c = rng.next(CONTIGS as u64) as usize;
+        if i % 2 == 0 {
+            let (start, _, _) = exons[c][rng.next(exons[c].len() as u64) as usize];
+            (c, (start + rng.next(200)).saturating_sub(READ_LEN / 2))
+        } else {
+            (c, rng.next(CONTIG_LEN))
+        }
+    }).collect()
+}
+
+fn bench_overlap(c: &mut Criterion) {
+    let mut rng = Lcg(42);
+    let exons = exons(&mut rng);
+    let reads = reads(&mut rng, &exons);
+    let index: Vec<IntervalIndex> = exons.iter().map(|v| IntervalIndex::new(v.clone())).collect();
+    let trees: Vec<IntervalTree<u64, FeatureStrand>> = exons.iter().map(|v| v.iter().map(|(s, e, strand)| (*s..*e, *strand)).collect()).collect();
+
+    let mut group = c.benchmark_group("overlap");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(READS as u64));
+    group.bench_function("IntervalIndex", |b| b.iter(|| {
+        reads.iter().map(|&(c, start)| index[c].strands(start, start + READ_LEN) as u64).sum::<u64>()
+    }));
+    group.bench_function("bio IntervalTree", |b| b.iter(|| {
+        reads.iter().map(|&(c, start)| {
+            let mut strands: u8 = 0;
+            for s in trees[c].find(start..start + READ_LEN) {
+                strands |= s.data().bit();
+            }
+            strands as u64
+        }).sum::<u64>()
+    }));
+    group.finish();
+}
+
+criterion_group!(benches, bench_overlap);
+criterion_main!(benches);
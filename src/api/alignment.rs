@@ -0,0 +1,135 @@
+use std::path::{Path, PathBuf};
+
+use crate::error::MyError;
+
+#[cfg(not(any(feature = "htslib", feature = "noodles")))]
+compile_error!("at least one of the htslib and noodles features must be enabled");
+
+/// sam flag中用到的bit
+/// 参考：https://samtools.github.io/hts-specs/SAMv1.pdf 1.4节
+pub struct SamFlag;
+
+impl SamFlag {
+    pub const PAIRED:          u16 = 1;
+    pub const READ_UNMAPPED:   u16 = 4;
+    pub const READ_REVERSE:    u16 = 16;
+    pub const FIRST_IN_PAIR:   u16 = 64;
+    pub const SECOND_IN_PAIR:  u16 = 128;
+    pub const NOT_PRIMARY_ALN: u16 = 256;
+    pub const FAIL_QC:         u16 = 512;
+    pub const DUPLICATE:       u16 = 1024;
+}
+
+/// 统计需要的record字段，与读取bam的后端无关，读取时重复使用同一个record，不需要每条read分配内存
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlignmentRecord {
+    pub tid:     i32,   // 比对到的contig在header中的下标，没有时为-1
+    pub pos:     i64,   // 0-based比对起始位置，没有时为-1
+    pub mapq:    u8,    // 比对质量，没有时为255
+    pub flags:   u16,   // sam flag
+    pub seq_len: usize, // 原始read长度
+}
+
+impl AlignmentRecord {
+    fn has_flag(&self, flag: u16) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.has_flag(SamFlag::PAIRED)
+    }
+
+    pub fn is_unmapped(&self) -> bool {
+        self.has_flag(SamFlag::READ_UNMAPPED)
+    }
+
+    pub fn is_reverse(&self) -> bool {
+        self.has_flag(SamFlag::READ_REVERSE)
+    }
+
+    pub fn is_first_in_template(&self) -> bool {
+        self.has_flag(SamFlag::FIRST_IN_PAIR)
+    }
+
+    pub fn is_last_in_template(&self) -> bool {
+        self.has_flag(SamFlag::SECOND_IN_PAIR)
+    }
+
+    pub fn is_secondary(&self) -> bool {
+        self.has_flag(SamFlag::NOT_PRIMARY_ALN)
+    }
+
+    pub fn is_quality_check_failed(&self) -> bool {
+        self.has_flag(SamFlag::FAIL_QC)
+    }
+
+    pub fn is_duplicate(&self) -> bool {
+        self.has_flag(SamFlag::DUPLICATE)
+    }
+}
+
+/// bam header中的contig名称和长度（@SQ SN、LN），下标为tid
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AlignmentHeader {
+    pub names:   Vec<String>,
+    pub lengths: Vec<u64>,
+}
+
+/// 按顺序读取sam/bam的record
+pub trait AlignmentReader {
+    fn header(&self) -> &AlignmentHeader;
+
+    /// 依次将每条record传给sink，sink返回false时停止读取
+    fn for_each_record(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError>;
+}
+
+/// 有索引的bam，可以只读取某个contig上的record
+pub trait IndexedAlignmentReader {
+    /// 将第tid个contig上的每条record传给sink，sink返回false时停止读取
+    fn for_each_record_in(&mut self, tid: usize, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError>;
+
+    /// 将没有比对位置（tid为-1）的每条record传给sink，这些record不属于任何contig，sink返回false时停止读取
+    fn for_each_unplaced(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError>;
+}
+
+/// 打开sam/bam文件，threads大于1时htslib使用线程池解压
+/// 编译了htslib时总是使用htslib，feature是叠加的，同时启用noodles不会改变后端；只启用noodles时使用noodles
+pub fn open_reader(file: &Path, threads: usize) -> Result<Box<dyn AlignmentReader>, MyError> {
+    #[cfg(feature = "htslib")]
+    {
+        Ok(Box::new(crate::bam_htslib::HtslibReader::from_path(file, threads)?))
+    }
+    #[cfg(not(feature = "htslib"))]
+    {
+        let _ = threads;
+        Ok(Box::new(crate::bam_noodles::NoodlesReader::from_path(file)?))
+    }
+}
+
+/// 查找sam/bam/cram的索引文件，与各后端查找的位置一致，没有时返回None
+/// htslib：file.bai、file.csi、file.crai，以及替换扩展名的bai、csi；只启用noodles时：file.bai、file.csi
+pub fn find_index(file: &Path) -> Option<PathBuf> {
+    let appended = |ext: &str| {
+        let mut name = file.as_os_str().to_owned();
+        name.push(".");
+        name.push(ext);
+        PathBuf::from(name)
+    };
+    #[cfg(feature = "htslib")]
+    let candidates = vec![appended("bai"), appended("csi"), appended("crai"), file.with_extension("bai"), file.with_extension("csi")];
+    #[cfg(not(feature = "htslib"))]
+    let candidates = vec![appended("bai"), appended("csi")];
+    candidates.into_iter().find(|p| p.is_file())
+}
+
+/// 打开有索引的bam文件，没有索引时返回错误，后端的选择与open_reader一致
+pub fn open_indexed(file: &Path) -> Result<Box<dyn IndexedAlignmentReader>, MyError> {
+    #[cfg(feature = "htslib")]
+    {
+        Ok(Box::new(crate::bam_htslib::HtslibIndexedReader::from_path(file)?))
+    }
+    #[cfg(not(feature = "htslib"))]
+    {
+        Ok(Box::new(crate::bam_noodles::NoodlesIndexedReader::from_path(file)?))
+    }
+}
@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use bio_types::strand::Strand;
+
+use crate::{
+    contig::{preview, ContigFilter},
+    counts::{ONLY_FORWARD, ONLY_REVERSE, ONLY_UNKNOWN},
+    error::MyError,
+};
+
+/// feature的链信息，bed和gtf统一使用这个类型，避免bed用"*"、gtf用""导致key不一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FeatureStrand {
+    Forward, // +
+    Reverse, // -
+    Unknown, // 没有链信息，bed中的"."或缺失，gtf中的"."
+}
+
+impl FeatureStrand {
+    /// bio_types的Strand转为FeatureStrand
+    pub fn from_strand(strand: Option<Strand>) -> Self {
+        match strand {
+            Some(Strand::Forward) => FeatureStrand::Forward,
+            Some(Strand::Reverse) => FeatureStrand::Reverse,
+            Some(Strand::Unknown) | None => FeatureStrand::Unknown,
+        }
+    }
+
+    /// 链信息集合中对应的bit
+    pub fn bit(&self) -> u8 {
+        match self {
+            FeatureStrand::Forward => ONLY_FORWARD as u8,
+            FeatureStrand::Reverse => ONLY_REVERSE as u8,
+            FeatureStrand::Unknown => ONLY_UNKNOWN as u8,
+        }
+    }
+}
+
+/// 无链信息feature的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnstrandedMode {
+    Skip,     // 加载注释时直接跳过
+    Separate, // 保留，只比对到无链信息feature的read单独统计
+}
+
+impl FromStr for UnstrandedMode {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(UnstrandedMode::Skip),
+            "separate" => Ok(UnstrandedMode::Separate),
+            _ => Err(MyError::ParaError{para: format!("-u only support skip or separate, not {}", s)}),
+        }
+    }
+}
+
+impl fmt::Display for UnstrandedMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnstrandedMode::Skip => write!(f, "skip"),
+            UnstrandedMode::Separate => write!(f, "separate"),
+        }
+    }
+}
+
+/// -r指定的注释文件格式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnnotationFormat {
+    Auto,     // 根据列数和内容自动判断
+    Bed,      // bed
+    RefFlat,  // UCSC refFlat
+    GenePred, // UCSC genePred、genePredExt、knownGene，以及带bin列的UCSC表格
+}
+
+impl FromStr for AnnotationFormat {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(AnnotationFormat::Auto),
+            "bed" => Ok(AnnotationFormat::Bed),
+            "refflat" => Ok(AnnotationFormat::RefFlat),
+            "genepred" => Ok(AnnotationFormat::GenePred),
+            _ => Err(MyError::ParaError{para: format!("-a only support auto, bed, refflat or genepred, not {}", s)}),
+        }
+    }
+}
+
+/// 注释文件解析方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    Strict,  // 遇到错误行直接报错，给出文件、行号和原因
+    Lenient, // 跳过错误行，最后报告跳过的行数
+}
+
+impl FromStr for ParseMode {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(ParseMode::Strict),
+            "lenient" => Ok(ParseMode::Lenient),
+            _ => Err(MyError::ParaError{para: format!("-p only support strict or lenient, not {}", s)}),
+        }
+    }
+}
+
+/// 基因模型，各格式的注释都转为这个类型，比如bed的一行、gtf/gff3选中的一个feature、genePred的一个转录本
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneModel {
+    pub chr:    String,
+    pub start:  u64,
+    pub end:    u64,
+    pub strand: FeatureStrand,
+    pub name:   Option<String>,           // feature名称，比如bed的name列、gtf的gene_id或transcript_id、gff3的ID
+    pub exons:  Option<Vec<(u64, u64)>>, // exon结构，bed12的block、refFlat/genePred的exonStarts/exonEnds
+}
+
+/// 读取注释时的统计
+#[derive(Debug, Clone, Default)]
+pub struct LoadStats {
+    pub skipped:  usize, // lenient模式下跳过的错误行数量
+    pub filtered: usize, // 被属性过滤条件排除的feature数量
+    pub unused_contigs: BTreeMap<String, usize>, // 不在bam header中的contig，value为跳过的feature数量
+}
+
+impl LoadStats {
+    /// contigs为None时保留所有feature，否则不在contigs中的feature计入unused_contigs
+    pub fn keep_contig(&mut self, contigs: Option<&ContigFilter>, chr: &str) -> bool {
+        match contigs {
+            Some(c) if !c.contains(chr) => {
+                *self.unused_contigs.entry(chr.to_string()).or_insert(0) += 1;
+                false
+            },
+            _ => true,
+        }
+    }
+}
+
+/// 注释来源，bed、gtf、gff3、refFlat/genePred都实现这个trait
+/// library使用者也可以实现这个trait，通过GeneRanges::from_source使用自己的注释来源
+pub trait AnnotationSource {
+    /// 依次读取每个基因模型交给sink处理，不需要把所有基因模型都存到内存中
+    /// contigs不为None时，解析过程中跳过不在contigs中的feature，并记录到LoadStats::unused_contigs
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError>;
+
+    /// 读取所有基因模型
+    fn models(&self) -> Result<Vec<GeneModel>, MyError> {
+        let mut models: Vec<GeneModel> = Vec::new();
+        self.for_each_model(None, &mut |m| models.push(m))?;
+        Ok(models)
+    }
+}
+
+/// 加载后的参考基因位置，key: chr, value: 该contig上的(start, end, strand)，统计前转换为IntervalIndex
+pub struct GeneRanges {
+    pub ranges:     HashMap<String, Vec<(u64, u64, FeatureStrand)>>,
+    pub unstranded: usize, // 无链信息的feature数量（skip时为跳过的数量）
+    pub skipped:    usize, // lenient模式下跳过的错误行数量
+    pub filtered:   usize, // 被属性过滤条件排除的feature数量
+    pub unused_contigs: BTreeMap<String, usize>, // 不在bam header中而跳过的contig及feature数量
+    pub renamed_contigs: BTreeMap<String, String>, // 转换为bam中名称的注释contig
+    pub features:   usize, // 加载的feature数量
+    pub max_end:    HashMap<String, u64>, // 每个contig上feature的最大坐标，用于和bam header中的长度比较
+    mode:           UnstrandedMode,
+}
+
+impl GeneRanges {
+    pub fn new(mode: UnstrandedMode) -> Self {
+        GeneRanges{
+            ranges: HashMap::new(),
+            unstranded: 0,
+            skipped: 0,
+            filtered: 0,
+            unused_contigs: BTreeMap::new(),
+            renamed_contigs: BTreeMap::new(),
+            features: 0,
+            max_end: HashMap::new(),
+            mode,
+        }
+    }
+
+    /// 从注释来源构建，use_exons为true时插入基因模型的每个exon（没有exon结构的插入整个区间）
+    /// contigs不为None时只加载这些contig上的feature，并将contig转换为bam中的名称
+    pub fn from_source(source: &dyn AnnotationSource, mode: UnstrandedMode, use_exons: bool, contigs: Option<&ContigFilter>) -> Result<Self, MyError> {
+        let mut gene_ranges = GeneRanges::new(mode);
+        let stats = source.for_each_model(contigs, &mut |mut m| {
+            if let Some(chr) = contigs.and_then(|c| c.resolve(&m.chr)) {
+                if chr != m.chr {
+                    gene_ranges.renamed_contigs.entry(m.chr.clone()).or_insert(chr.to_string());
+                    m.chr = chr.to_string();
+                }
+            }
+            gene_ranges.add(&m, use_exons)
+        })?;
+        gene_ranges.skipped += stats.skipped;
+        gene_ranges.filtered += stats.filtered;
+        for (chr, n) in stats.unused_contigs {
+            *gene_ranges.unused_contigs.entry(chr).or_insert(0) += n;
+        }
+        Ok(gene_ranges)
+    }
+
+    /// 插入一个基因模型，根据mode决定是否跳过无链信息的基因模型
+    pub fn add(&mut self, model: &GeneModel, use_exons: bool) {
+        if model.strand == FeatureStrand::Unknown {
+            self.unstranded += 1;
+            if self.mode == UnstrandedMode::Skip {
+                return
+            }
+        }
+        self.features += 1;
+        match (&model.exons, use_exons) {
+            (Some(exons), true) => {
+                for (start, end) in exons {
+                    self.insert(&model.chr, *start, *end, model.strand);
+                }
+            },
+            _ => self.insert(&model.chr, model.start, model.end, model.strand),
+        }
+    }
+
+    /// 插入一个区间，end小于start的区间（比如自定义注释来源没有检查）计入skipped
+    fn insert(&mut self, chr: &str, start: u64, end: u64, strand: FeatureStrand) {
+        if end < start {
+            self.skipped += 1;
+            return
+        }
+        match self.ranges.get_mut(chr) {
+            Some(v) => v.push((start, end, strand)),
+            None => {
+                self.ranges.insert(chr.to_string(), vec![(start, end, strand)]);
+            },
+        }
+        match self.max_end.get_mut(chr) {
+            Some(m) => *m = (*m).max(end),
+            None => {
+                self.max_end.insert(chr.to_string(), end);
+            },
+        }
+    }
+
+    /// 打印无链信息feature的数量，lenient模式下跳过的错误行数量，以及没用到的注释contig
+    pub fn report(&self) {
+        if self.skipped > 0 {
+            println!("{} malformed annotation lines were skipped", self.skipped);
+        }
+        if self.filtered > 0 {
+            println!("{} features were excluded by attribute filters", self.filtered);
+        }
+        if !self.renamed_contigs.is_empty() {
+            println!("{} annotation contigs were renamed to match the BAM header: {}", self.renamed_contigs.len(), preview(self.renamed_contigs.iter().map(|(a, b)| format!("{} -> {}", a, b)).collect()));
+        }
+        if !self.unused_contigs.is_empty() {
+            let n: usize = self.unused_contigs.values().sum();
+            println!("{} features on {} annotation contigs absent from the BAM header were skipped: {}", n, self.unused_contigs.len(), preview(self.unused_contigs.iter().map(|(chr, n)| format!("{} ({})", chr, n)).collect()));
+        }
+        match self.mode {
+            UnstrandedMode::Skip => println!("{} unstranded features were skipped", self.unstranded),
+            UnstrandedMode::Separate => println!("{} unstranded features were loaded", self.unstranded),
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+
+use rust_htslib::bam::{FetchDefinition, IndexedReader, Read, Reader, Record};
+
+use crate::{
+    alignment::{AlignmentHeader, AlignmentReader, AlignmentRecord, IndexedAlignmentReader},
+    error::MyError,
+};
+
+/// 使用rust-htslib读取sam/bam/cram，默认的后端
+pub struct HtslibReader {
+    file:   PathBuf,
+    reader: Reader,
+    header: AlignmentHeader,
+}
+
+impl HtslibReader {
+    /// threads大于1时使用htslib的线程池解压
+    pub fn from_path(file: &Path, threads: usize) -> Result<Self, MyError> {
+        //let mut bam_reader = rust_htslib::bam::IndexedReader::from_path(bam_file).map_err(|e| MyError::ReadBamError{file: bam_file.to_str().unwrap().to_string(), error: e})?; // 这样读取会导致下面循环读取record时返回None退出循环
+        let mut reader = Reader::from_path(file).map_err(|e| MyError::ReadBamError{file: file.display().to_string(), error: e})?;
+        if threads > 1 {
+            reader.set_threads(threads).map_err(|e| MyError::ReadBamError{file: file.display().to_string(), error: e})?;
+        }
+        // 需要根据tid从header中获取chr，参考：https://github.com/rust-bio/rust-htslib/issues/288
+        let view = reader.header();
+        let header = AlignmentHeader{
+            names: view.target_names().iter().map(|n| String::from_utf8_lossy(n).to_string()).collect(),
+            lengths: (0..view.target_count()).map(|tid| view.target_len(tid).unwrap_or(0)).collect(),
+        };
+        Ok(HtslibReader{file: file.to_path_buf(), reader, header})
+    }
+}
+
+impl AlignmentReader for HtslibReader {
+    fn header(&self) -> &AlignmentHeader {
+        &self.header
+    }
+
+    fn for_each_record(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        // 先声明一个record，后面使用bam的read方法往里面写入新record，避免每次重新声明record，更高效
+        let mut record = Record::new();
+        let mut converted = AlignmentRecord::default();
+        while let Some(result) = self.reader.read(&mut record) {
+            // 这里result是`Result<()>`解析下没报错就行
+            result.map_err(|e| MyError::ReadBamRecordError{file: self.file.display().to_string(), error: e})?;
+            convert(&record, &mut converted);
+            if !sink(&converted) {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 使用rust-htslib读取有索引的bam/cram
+pub struct HtslibIndexedReader {
+    file:   PathBuf,
+    reader: IndexedReader,
+}
+
+impl HtslibIndexedReader {
+    pub fn from_path(file: &Path) -> Result<Self, MyError> {
+        let reader = IndexedReader::from_path(file).map_err(|e| MyError::ReadBamError{file: file.display().to_string(), error: e})?;
+        Ok(HtslibIndexedReader{file: file.to_path_buf(), reader})
+    }
+
+    /// 读取fetch到的record
+    fn read_fetched(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        let mut record = Record::new();
+        let mut converted = AlignmentRecord::default();
+        while let Some(result) = self.reader.read(&mut record) {
+            result.map_err(|e| MyError::ReadBamRecordError{file: self.file.display().to_string(), error: e})?;
+            convert(&record, &mut converted);
+            if !sink(&converted) {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IndexedAlignmentReader for HtslibIndexedReader {
+    fn for_each_record_in(&mut self, tid: usize, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        self.reader.fetch(FetchDefinition::CompleteTid(tid as i32)).map_err(|e| MyError::ReadBamError{file: self.file.display().to_string(), error: e})?;
+        self.read_fetched(sink)
+    }
+
+    fn for_each_unplaced(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        self.reader.fetch(FetchDefinition::Unmapped).map_err(|e| MyError::ReadBamError{file: self.file.display().to_string(), error: e})?;
+        self.read_fetched(sink)
+    }
+}
+
+/// 取出统计需要的字段
+fn convert(record: &Record, converted: &mut AlignmentRecord) {
+    converted.tid = record.tid();
+    converted.pos = record.pos();
+    converted.mapq = record.mapq();
+    converted.flags = record.flags();
+    converted.seq_len = record.seq_len();
+}
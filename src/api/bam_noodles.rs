@@ -0,0 +1,267 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+
+use noodles::{bam, bgzf, core::Region, sam};
+
+use crate::{
+    alignment::{AlignmentHeader, AlignmentReader, AlignmentRecord, IndexedAlignmentReader},
+    error::MyError,
+    utils::Compression,
+};
+
+/// 使用纯Rust的noodles读取sam/bam，不需要C编译环境，方便静态编译，启用noodles feature时使用
+/// 目前不支持cram，bam只使用单线程解压
+/// 参考：https://github.com/zaeleus/noodles
+pub struct NoodlesReader {
+    file:   PathBuf,
+    inner:  Inner,
+    header: AlignmentHeader,
+}
+
+/// 根据文件内容判断是bam还是sam
+/// sam的reader和header比bam的reader大很多，放到Box中，避免enum按最大的variant占用空间
+enum Inner {
+    Bam(bam::io::Reader<bgzf::Reader<BufReader<File>>>),
+    Sam(Box<(sam::io::Reader<BufReader<File>>, sam::Header)>), // sam的record需要header才能得到tid
+}
+
+impl NoodlesReader {
+    pub fn from_path(file: &Path) -> Result<Self, MyError> {
+        let opened_file = File::open(file).map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+        let mut buf_reader = BufReader::new(opened_file);
+        // 只查看开头的字节，bgzip压缩的是bam，"CRAM"开头的是cram，其他当作sam
+        let magic = buf_reader.fill_buf().map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+        if magic.starts_with(b"CRAM") {
+            return Err(MyError::ReadAlignmentError{file: file.display().to_string(), error: io::Error::new(io::ErrorKind::Unsupported, "CRAM is not supported by the noodles backend")})
+        }
+        let (inner, header) = match Compression::detect(magic) {
+            Compression::Bgzf | Compression::Gzip => {
+                let mut reader = bam::io::Reader::new(buf_reader);
+                let header = reader.read_header().map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+                (Inner::Bam(reader), convert_header(&header))
+            },
+            _ => {
+                let mut reader = sam::io::Reader::new(buf_reader);
+                let header = reader.read_header().map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+                let converted = convert_header(&header);
+                (Inner::Sam(Box::new((reader, header))), converted)
+            },
+        };
+        Ok(NoodlesReader{file: file.to_path_buf(), inner, header})
+    }
+}
+
+impl AlignmentReader for NoodlesReader {
+    fn header(&self) -> &AlignmentHeader {
+        &self.header
+    }
+
+    fn for_each_record(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        let file = self.file.display().to_string();
+        let mut converted = AlignmentRecord::default();
+        match &mut self.inner {
+            Inner::Bam(reader) => {
+                // 重复使用同一个record，避免每条read分配内存
+                let mut record = bam::Record::default();
+                while reader.read_record(&mut record).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})? > 0 {
+                    convert_bam(&record, &mut converted).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+                    if !sink(&converted) {
+                        break
+                    }
+                }
+            },
+            Inner::Sam(inner) => {
+                let (reader, header) = &mut **inner;
+                let mut record = sam::Record::default();
+                while reader.read_record(&mut record).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})? > 0 {
+                    convert_sam(&record, header, &mut converted).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+                    if !sink(&converted) {
+                        break
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+/// 使用noodles读取有索引（.bai）的bam
+pub struct NoodlesIndexedReader {
+    file:   PathBuf,
+    reader: bam::io::IndexedReader<bgzf::Reader<File>>,
+    header: sam::Header,
+}
+
+impl NoodlesIndexedReader {
+    pub fn from_path(file: &Path) -> Result<Self, MyError> {
+        let mut reader = bam::io::indexed_reader::Builder::default().build_from_path(file).map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+        let header = reader.read_header().map_err(|e| MyError::ReadAlignmentError{file: file.display().to_string(), error: e})?;
+        Ok(NoodlesIndexedReader{file: file.to_path_buf(), reader, header})
+    }
+}
+
+impl IndexedAlignmentReader for NoodlesIndexedReader {
+    fn for_each_record_in(&mut self, tid: usize, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        let file = self.file.display().to_string();
+        let name = match self.header.reference_sequences().get_index(tid) {
+            Some((name, _)) => name.clone(),
+            None => return Ok(()),
+        };
+        // 查询整条contig
+        let region = Region::new(name, ..);
+        let query = self.reader.query(&self.header, &region).map_err(|e| MyError::ReadAlignmentError{file: file.clone(), error: e})?;
+        let mut converted = AlignmentRecord::default();
+        for result in query {
+            let record = result.map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+            convert_bam(&record, &mut converted).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+            if !sink(&converted) {
+                break
+            }
+        }
+        Ok(())
+    }
+
+    fn for_each_unplaced(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+        let file = self.file.display().to_string();
+        let query = self.reader.query_unmapped().map_err(|e| MyError::ReadAlignmentError{file: file.clone(), error: e})?;
+        let mut converted = AlignmentRecord::default();
+        for result in query {
+            let record = result.map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+            convert_bam(&record, &mut converted).map_err(|e| MyError::ReadAlignmentRecordError{file: file.clone(), error: e})?;
+            // query_unmapped从最后一个contig的第一条record开始读取，有位置的未比对read已经在for_each_record_in中读取
+            if converted.tid >= 0 {
+                continue
+            }
+            if !sink(&converted) {
+                break
+            }
+        }
+        Ok(())
+    }
+}
+
+/// header中@SQ的名称和长度
+fn convert_header(header: &sam::Header) -> AlignmentHeader {
+    let (names, lengths) = header.reference_sequences().iter()
+        .map(|(name, rs)| (name.to_string(), rs.length().get() as u64))
+        .unzip();
+    AlignmentHeader{names, lengths}
+}
+
+/// 取出bam record中统计需要的字段，noodles的坐标为1-based，转为和htslib一致的0-based
+fn convert_bam(record: &bam::Record, converted: &mut AlignmentRecord) -> io::Result<()> {
+    converted.tid = record.reference_sequence_id().transpose()?.map(|id| id as i32).unwrap_or(-1);
+    converted.pos = record.alignment_start().transpose()?.map(|p| usize::from(p) as i64 - 1).unwrap_or(-1);
+    converted.mapq = record.mapping_quality().map(u8::from).unwrap_or(255);
+    converted.flags = record.flags().bits();
+    converted.seq_len = record.sequence().len();
+    Ok(())
+}
+
+/// 取出sam record中统计需要的字段
+fn convert_sam(record: &sam::Record, header: &sam::Header, converted: &mut AlignmentRecord) -> io::Result<()> {
+    converted.tid = record.reference_sequence_id(header).transpose()?.map(|id| id as i32).unwrap_or(-1);
+    converted.pos = record.alignment_start().transpose()?.map(|p| usize::from(p) as i64 - 1).unwrap_or(-1);
+    converted.mapq = record.mapping_quality().transpose()?.map(u8::from).unwrap_or(255);
+    converted.flags = record.flags()?.bits();
+    converted.seq_len = record.sequence().len();
+    Ok(())
+}
+
+#[cfg(all(test, feature = "htslib"))]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use rust_htslib::bam::{self as hts, Read as _};
+
+    use crate::bam_htslib::{HtslibIndexedReader, HtslibReader};
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_noodles_{}_{}", std::process::id(), name))
+    }
+
+    /// 按坐标排序的sam，包含双端、反向、mapq为0和255、没有序列、未比对但有位置、完全未比对的read
+    const SAM: &str = "@HD\tVN:1.6\tSO:coordinate\n\
+@SQ\tSN:chr1\tLN:10000\n\
+@SQ\tSN:chr2\tLN:8000\n\
+r1\t0\tchr1\t1\t60\t10M\t*\t0\t0\tACGTACGTAC\t*\n\
+r2\t99\tchr1\t100\t0\t5M\t=\t200\t105\tACGTA\t*\n\
+r3\t16\tchr1\t150\t255\t4M2I4M\t*\t0\t0\tACGTACGTAC\t*\n\
+r2\t147\tchr1\t200\t60\t5M\t=\t100\t-105\tACGTA\t*\n\
+r4\t1105\tchr1\t300\t30\t8M\t*\t0\t0\t*\t*\n\
+r5\t69\tchr1\t400\t0\t*\t=\t400\t0\tACGT\t*\n\
+r5\t137\tchr1\t400\t60\t4M\t=\t400\t0\tACGT\t*\n\
+r6\t784\tchr2\t7990\t12\t3S7M\t*\t0\t0\tACGTACGTAC\t*\n\
+r7\t4\t*\t0\t0\t*\t*\t0\t0\tACGTAC\t*\n";
+
+    fn collect(reader: &mut dyn AlignmentReader) -> Vec<AlignmentRecord> {
+        let mut records: Vec<AlignmentRecord> = Vec::new();
+        reader.for_each_record(&mut |r| {
+            records.push(r.clone());
+            true
+        }).unwrap();
+        records
+    }
+
+    fn collect_in(reader: &mut dyn IndexedAlignmentReader, tid: usize) -> Vec<AlignmentRecord> {
+        let mut records: Vec<AlignmentRecord> = Vec::new();
+        reader.for_each_record_in(tid, &mut |r| {
+            records.push(r.clone());
+            true
+        }).unwrap();
+        records
+    }
+
+    fn collect_unplaced(reader: &mut dyn IndexedAlignmentReader) -> Vec<AlignmentRecord> {
+        let mut records: Vec<AlignmentRecord> = Vec::new();
+        reader.for_each_unplaced(&mut |r| {
+            records.push(r.clone());
+            true
+        }).unwrap();
+        records
+    }
+
+    #[test]
+    fn noodles_matches_htslib() {
+        let sam_file = temp_file("reads.sam");
+        let bam_file = temp_file("reads.bam");
+        fs::write(&sam_file, SAM).unwrap();
+        {
+            let mut reader = hts::Reader::from_path(&sam_file).unwrap();
+            let header = hts::Header::from_template(reader.header());
+            let mut writer = hts::Writer::from_path(&bam_file, &header, hts::Format::Bam).unwrap();
+            for record in reader.records() {
+                writer.write(&record.unwrap()).unwrap();
+            }
+        }
+        hts::index::build(&bam_file, None, hts::index::Type::Bai, 1).unwrap();
+
+        for file in [&sam_file, &bam_file] {
+            let mut htslib = HtslibReader::from_path(file, 1).unwrap();
+            let mut noodles = NoodlesReader::from_path(file).unwrap();
+            assert_eq!(noodles.header(), htslib.header());
+            let expected = collect(&mut htslib);
+            assert_eq!(expected.len(), 9);
+            assert_eq!(collect(&mut noodles), expected, "{}", file.display());
+        }
+        let mut htslib = HtslibIndexedReader::from_path(&bam_file).unwrap();
+        let mut noodles = NoodlesIndexedReader::from_path(&bam_file).unwrap();
+        for tid in 0..2 {
+            assert_eq!(collect_in(&mut noodles, tid), collect_in(&mut htslib, tid), "tid = {}", tid);
+        }
+        // 只有r7没有比对位置
+        let unplaced = collect_unplaced(&mut htslib);
+        assert_eq!(unplaced.iter().map(|r| (r.tid, r.seq_len)).collect::<Vec<_>>(), vec![(-1, 6)]);
+        assert_eq!(collect_unplaced(&mut noodles), unplaced);
+
+        let mut index = bam_file.as_os_str().to_owned();
+        index.push(".bai");
+        for file in [sam_file, bam_file, PathBuf::from(index)] {
+            fs::remove_file(file).unwrap();
+        }
+    }
+}
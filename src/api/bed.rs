@@ -1,47 +1,169 @@
-use std::collections::HashMap;
-use std::path::Path;
+use std::io::BufRead;
+use std::path::PathBuf;
 
-use bio::{
-    data_structures::interval_tree::IntervalTree,
-    io::bed::Reader,
-    utils::Interval,
-};
-use bio_types::strand::Strand;
+use anyhow::anyhow;
 
 use crate::{
+    annotation::{AnnotationSource, FeatureStrand, GeneModel, LoadStats, ParseMode},
+    contig::ContigFilter,
     error::MyError,
     utils::my_reader,
 };
 
-/// 读取指定参考基因bed文件
-/// infer_experiment.py使用bx-python的IntervalTree存储bed位置，这里使用rust-bio的IntervalTree
-/// https://github.com/rust-bio/rust-bio/issues/459
-/// https://docs.rs/bio/latest/bio/data_structures/interval_tree/struct.IntervalTree.html
-/// https://docs.rs/bio/2.2.0/bio/io/bed/index.html
-pub fn load_bed(ref_bed: &Path) -> Result<HashMap<String, IntervalTree<u64, String>>, MyError> {
-    // 读取bed文件
-    //let mut bed_reader = Reader::from_file(ref_bed).map_err(|e| MyError::ReadBedError{file: ref_bed.to_str().unwrap().to_string(), error: e.into()})?;
-    let mut bed_reader = Reader::new(my_reader(ref_bed)?); // 使用my_reader支持读取bed或bed.gz
-    let mut records = bed_reader.records();
-    // 存储bed位置和链信息，相同chr存储在一起
-    let mut gene_ranges: HashMap<String, IntervalTree<u64, String>> = HashMap::new(); // key: chr, value: IntervalTree
-    // 遍历每个record
-    let mut chr: String;
-    while let Some(Ok(record)) = records.next() {
-        chr = record.chrom().to_string();
-        if !gene_ranges.contains_key(&chr) {
-            gene_ranges.insert(chr.clone(), IntervalTree::new());
+/// 参考基因bed文件
+/// infer_experiment.py使用bx-python的IntervalTree存储bed位置，这里使用排序数组实现的IntervalIndex（overlap.rs）
+/// 逐行解析，而不是用bio::io::bed::Reader，这样可以知道出错的行号，并跳过track、browser、#开头的行
+/// strict遇到错误行直接报错，lenient跳过错误行并计数
+pub struct BedSource {
+    pub path:       PathBuf,
+    pub parse_mode: ParseMode,
+    pub threads:    usize, // bgzip压缩时的解压线程数
+}
+
+impl AnnotationSource for BedSource {
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+        let mut stats = LoadStats::default();
+        // 读取bed文件
+        let bed_reader = my_reader(&self.path, self.threads)?; // 使用my_reader支持读取bed或bed.gz
+        // 遍历每一行
+        for (i, line) in bed_reader.lines().enumerate() {
+            let line = line.map_err(|e| MyError::LinesError{file: self.path.display().to_string(), error: e})?;
+            // 跳过空行和header行
+            if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+                continue
+            }
+            // 先解析再按contig过滤，这样strict模式下其他contig上的错误行也会报错
+            match parse_bed_line(&line) {
+                Ok(model) => if stats.keep_contig(contigs, &model.chr) {
+                    sink(model)
+                },
+                Err(reason) => match self.parse_mode {
+                    ParseMode::Strict => return Err(MyError::ReadBedError{file: self.path.display().to_string(), error: anyhow!("line {}: {}", i + 1, reason)}),
+                    ParseMode::Lenient => stats.skipped += 1,
+                },
+            }
         }
-        let strand = match record.strand() {
-            Some(s) => match s {
-                Strand::Forward => "+".to_string(),
-                Strand::Reverse => "-".to_string(),
-                Strand::Unknown => "*".to_string(),
-            },
-            None => "*".to_string(),
-        };
-        let tree = gene_ranges.get_mut(&chr).unwrap();
-        tree.insert(Interval::new(record.start()..record.end()).unwrap(), strand);
-    }
-    Ok(gene_ranges)
+        Ok(stats)
+    }
+}
+
+/// 解析bed的一行，bed12时根据blockSizes和blockStarts得到exon结构，出错时返回错误原因
+fn parse_bed_line(line: &str) -> Result<GeneModel, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() < 3 {
+        return Err(format!("expected at least 3 columns, found {}", fields.len()))
+    }
+    let start: u64 = fields[1].trim().parse().map_err(|_| format!("invalid start {:?}", fields[1]))?;
+    let end: u64 = fields[2].trim().parse().map_err(|_| format!("invalid end {:?}", fields[2]))?;
+    // 长度为0的feature是合法的bed（比如插入位点），只排除end小于start的
+    if end < start {
+        return Err(format!("inverted interval {}-{}", start, end))
+    }
+    let strand = match fields.get(5).map(|s| s.trim()) {
+        Some("+") => FeatureStrand::Forward,
+        Some("-") => FeatureStrand::Reverse,
+        Some(".") | Some("") | None => FeatureStrand::Unknown,
+        Some(s) => return Err(format!("invalid strand {:?}", s)),
+    };
+    let exons = if fields.len() >= 12 {
+        let sizes = parse_blocks(fields[10]).ok_or(format!("invalid blockSizes {:?}", fields[10]))?;
+        let starts = parse_blocks(fields[11]).ok_or(format!("invalid blockStarts {:?}", fields[11]))?;
+        if sizes.len() != starts.len() {
+            return Err(format!("found {} blockSizes but {} blockStarts", sizes.len(), starts.len()))
+        }
+        Some(starts.into_iter().zip(sizes).map(|(s, l)| (start + s, start + s + l)).collect())
+    } else {
+        None
+    };
+    Ok(GeneModel{
+        chr: fields[0].to_string(),
+        start,
+        end,
+        strand,
+        name: fields.get(3).map(|s| s.to_string()),
+        exons,
+    })
+}
+
+/// 解析逗号分隔的blockSizes或blockStarts，比如"354,109,1189,"
+fn parse_blocks(s: &str) -> Option<Vec<u64>> {
+    s.split(',').filter(|c| !c.trim().is_empty()).map(|c| c.trim().parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_bed_{}_{}", std::process::id(), name))
+    }
+
+    /// 写入bed文件后读取，返回读到的基因模型和统计
+    fn load(name: &str, content: &str, parse_mode: ParseMode, contigs: Option<&ContigFilter>) -> (Result<LoadStats, MyError>, Vec<GeneModel>) {
+        let path = temp_file(name);
+        std::fs::write(&path, content).unwrap();
+        let mut models = Vec::new();
+        let result = BedSource{path: path.clone(), parse_mode, threads: 1}.for_each_model(contigs, &mut |m| models.push(m));
+        std::fs::remove_file(&path).unwrap();
+        (result, models)
+    }
+
+    #[test]
+    fn parse_lines() {
+        // bed6
+        let m = parse_bed_line("chr1\t100\t200\tg1\t0\t-").unwrap();
+        assert_eq!((m.chr.as_str(), m.start, m.end, m.strand, m.name.as_deref(), m.exons), ("chr1", 100, 200, FeatureStrand::Reverse, Some("g1"), None));
+        // bed3没有strand
+        assert_eq!(parse_bed_line("chr1\t100\t200").unwrap().strand, FeatureStrand::Unknown);
+        // bed12的block转为exon
+        let m = parse_bed_line("chr1\t100\t1000\tg2\t0\t+\t100\t1000\t0\t2\t50,100,\t0,800,").unwrap();
+        assert_eq!(m.exons, Some(vec![(100, 150), (900, 1000)]));
+        // 长度为0的feature是合法的
+        let m = parse_bed_line("chr1\t100\t100\tins\t0\t+").unwrap();
+        assert_eq!((m.start, m.end), (100, 100));
+        // 错误行
+        assert_eq!(parse_bed_line("chr1\t200\t100").unwrap_err(), "inverted interval 200-100");
+        assert_eq!(parse_bed_line("chr1\t100").unwrap_err(), "expected at least 3 columns, found 2");
+        assert_eq!(parse_bed_line("chr1\t1e3\t2000").unwrap_err(), "invalid start \"1e3\"");
+        assert_eq!(parse_bed_line("chr1\t100\t200\tg\t0\t*").unwrap_err(), "invalid strand \"*\"");
+        assert_eq!(parse_bed_line("chr1\t100\t1000\tg\t0\t+\t100\t1000\t0\t2\t50,100,\t0,").unwrap_err(), "found 2 blockSizes but 1 blockStarts");
+    }
+
+    #[test]
+    fn skip_header_lines() {
+        let content = "track name=genes\nbrowser position chr1:1-1000\n# comment\n\nchr1\t100\t200\tg1\t0\t+\nchr2\t300\t400\tg2\t0\t-\n";
+        let (result, models) = load("header", content, ParseMode::Strict, None);
+        let stats = result.unwrap();
+        assert_eq!(models.iter().map(|m| m.name.as_deref().unwrap()).collect::<Vec<_>>(), vec!["g1", "g2"]);
+        assert_eq!(stats.skipped, 0);
+    }
+
+    #[test]
+    fn strict_and_lenient() {
+        // 第4行end小于start，第5行列数不足
+        let content = "# comment\nchr1\t100\t200\tg1\t0\t+\nchr1\t100\t100\tins\t0\t+\nchr1\t500\t400\tg2\t0\t+\nchr2\t300\nchr2\t300\t400\tg3\t0\t-\n";
+        let (result, models) = load("strict", content, ParseMode::Strict, None);
+        let error = result.unwrap_err().to_string();
+        assert!(error.contains("infer_experiment_bed_") && error.contains("line 4: inverted interval 500-400"), "{}", error);
+        assert_eq!(models.len(), 2); // 报错前的feature已经交给sink
+        let (result, models) = load("lenient", content, ParseMode::Lenient, None);
+        assert_eq!(result.unwrap().skipped, 2);
+        assert_eq!(models.iter().map(|m| m.name.as_deref().unwrap()).collect::<Vec<_>>(), vec!["g1", "ins", "g3"]);
+    }
+
+    #[test]
+    fn parse_before_contig_filter() {
+        let contigs = ContigFilter::new(&["chr1".to_string()], &[1000]);
+        // 不在bam中的contig上的错误行，strict模式下也要报错
+        let content = "chr1\t100\t200\tg1\t0\t+\nchrUn\t300\t200\tbad\t0\t+\nchrUn\t300\t400\tg2\t0\t+\n";
+        let (result, _) = load("filter_strict", content, ParseMode::Strict, Some(&contigs));
+        assert!(result.unwrap_err().to_string().contains("line 2: inverted interval 300-200"));
+        // lenient模式下错误行计入skipped，不计入unused_contigs
+        let (result, models) = load("filter_lenient", content, ParseMode::Lenient, Some(&contigs));
+        let stats = result.unwrap();
+        assert_eq!(stats.skipped, 1);
+        assert_eq!(stats.unused_contigs.into_iter().collect::<Vec<_>>(), vec![("chrUn".to_string(), 1)]);
+        assert_eq!(models.iter().map(|m| m.name.as_deref().unwrap()).collect::<Vec<_>>(), vec!["g1"]);
+    }
 }
@@ -0,0 +1,426 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::{
+    annotation::{AnnotationSource, FeatureStrand, GeneModel, LoadStats},
+    contig::ContigFilter,
+    error::MyError,
+    parse_paras::IndexParas,
+};
+
+/// 缓存文件开头的magic bytes，最后一位是格式版本
+pub const CACHE_MAGIC: &[u8; 8] = b"IEXPIDX\x02";
+
+/// 解析后的注释区间二进制缓存，避免每次运行都重新解析整个gtf
+/// 格式（小端）：
+/// magic(8) | 源注释crc32(u32) | 源注释大小(u64) | 源注释路径(str) | 描述(str，格式、feature、过滤条件等)
+/// | 参数数量(u32) | 每个参数: 参数名(str) 值(str) | skipped(u64) | filtered(u64)
+/// | chr数量(u32) | 每个chr: 名称(str) 区间数量(u32) 每个区间: start与上一个start的差值(varint) 长度(varint) strand(u8)
+/// | 前面所有字节的crc32(u32)
+/// str为u32长度加UTF-8字节，区间按start排序后差值编码，体积比原始注释小很多
+/// expected为命令行中明确指定的参数，与建立缓存时的参数不一致时报错（-p只影响解析，只给出警告）
+/// 源注释文件大小改变时给出警告，verify_source为true时重新计算源注释的crc32，改变则报错
+pub struct CacheSource {
+    pub path:          PathBuf,
+    pub expected:      Vec<(&'static str, String)>,
+    pub verify_source: bool,
+}
+
+impl AnnotationSource for CacheSource {
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+        let cache = read_cache(&self.path)?;
+        println!("Load annotation cache {}, built from {} (crc32: {:08x}), {}", self.path.display(), cache.source, cache.source_crc32, cache.description);
+        self.check_options(&cache)?;
+        self.check_source(&cache)?;
+        let mut stats = cache.stats;
+        for (chr, intervals) in cache.intervals {
+            if contigs.is_some_and(|c| !c.contains(&chr)) {
+                *stats.unused_contigs.entry(chr).or_insert(0) += intervals.len();
+                continue
+            }
+            for (start, end, strand) in intervals {
+                sink(GeneModel{chr: chr.clone(), start, end, strand, name: None, exons: None});
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl CacheSource {
+    /// 比较命令行中明确指定的参数和建立缓存时的参数
+    fn check_options(&self, cache: &Cache) -> Result<(), MyError> {
+        for (option, value) in &self.expected {
+            let cached = cache.options.iter().find(|(o, _)| o == option).map(|(_, v)| v.as_str()).unwrap_or("");
+            if cached == value {
+                continue
+            }
+            let show = |v: &str| if v.is_empty() { "none".to_string() } else { v.to_string() };
+            if *option == "-p" {
+                println!("Warning - {} {} is ignored, the annotation cache was built with {} {}", option, value, option, cached);
+            } else {
+                return Err(MyError::ParaError{para: format!("{} {} differs from the annotation cache {}, which was built with {} {}, rebuild the cache with the index subcommand or drop {}", option, show(value), self.path.display(), option, show(cached), option)})
+            }
+        }
+        Ok(())
+    }
+
+    /// 检查源注释是否在建立缓存后改变，源注释不存在时无法检查，verify_source时报错，否则给出警告
+    fn check_source(&self, cache: &Cache) -> Result<(), MyError> {
+        let source = Path::new(&cache.source);
+        if !source.is_file() {
+            if self.verify_source {
+                return Err(MyError::ReadCacheError{file: self.path.display().to_string(), error: anyhow!("source annotation {} not found, cannot verify the cache", cache.source)})
+            }
+            println!("Warning - source annotation {} not found, cannot check whether it changed since the cache was built", cache.source);
+            return Ok(())
+        }
+        if self.verify_source {
+            let (crc32, size) = file_crc32(source)?;
+            if crc32 != cache.source_crc32 || size != cache.source_size {
+                return Err(MyError::ReadCacheError{file: self.path.display().to_string(), error: anyhow!("source annotation {} changed since the cache was built (crc32 {:08x}, now {:08x}), rebuild the cache with the index subcommand", cache.source, cache.source_crc32, crc32)})
+            }
+        } else if let Ok(meta) = fs::metadata(source) {
+            if meta.len() != cache.source_size {
+                println!("Warning - source annotation {} changed since the cache was built (size {}, now {}), rebuild the cache with the index subcommand", cache.source, cache.source_size, meta.len());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 一个chr的区间，(start, end, strand)
+pub type ChrIntervals = Vec<(u64, u64, FeatureStrand)>;
+
+/// 读取的缓存内容
+pub struct Cache {
+    pub source:       String,   // 源注释的绝对路径
+    pub source_crc32: u32,      // 源注释crc32
+    pub source_size:  u64,      // 源注释大小
+    pub description:  String,   // 格式、feature、过滤条件等
+    pub options:      Vec<(String, String)>, // 建立缓存时的参数，(参数名, 值)
+    pub stats:        LoadStats,
+    pub intervals:    Vec<(String, ChrIntervals)>, // 每个chr的区间
+}
+
+/// 判断文件是否为缓存文件
+pub fn is_cache(file: &Path) -> Result<bool, MyError> {
+    let mut magic = [0u8; 8];
+    let mut f = File::open(file).map_err(|e| MyError::OpenFileError{file: file.display().to_string(), error: e})?;
+    match f.read_exact(&mut magic) {
+        Ok(()) => Ok(&magic == CACHE_MAGIC),
+        Err(_) => Ok(false), // 不足8字节
+    }
+}
+
+/// index子命令，解析注释并写入缓存
+pub fn run_index(paras: &IndexParas) -> Result<(), MyError> {
+    let annotation = &paras.annotation;
+    let source_path = annotation.path()?;
+    if is_cache(source_path)? {
+        return Err(MyError::ParaError{para: format!("{} is already an annotation cache", source_path.display())})
+    }
+    let description = annotation.description()?;
+    let options = annotation.cache_options(annotation.format_name()?).into_iter().map(|(o, v)| (o.to_string(), v)).collect();
+    let source = annotation.annotation_source(paras.threads)?;
+    // 按chr存储，-f exon时展开exon结构，与GeneRanges::from_source一致
+    let use_exons = annotation.use_exons();
+    let mut intervals: HashMap<String, ChrIntervals> = HashMap::new();
+    let stats = source.for_each_model(None, &mut |m| {
+        let chr_intervals = intervals.entry(m.chr).or_default();
+        match (m.exons, use_exons) {
+            (Some(exons), true) => chr_intervals.extend(exons.into_iter().map(|(s, e)| (s, e, m.strand))),
+            _ => chr_intervals.push((m.start, m.end, m.strand)),
+        }
+    })?;
+    let (source_crc32, source_size) = file_crc32(source_path)?;
+    // 记录绝对路径，在其他目录使用缓存时也能找到源注释
+    let source_path = fs::canonicalize(source_path).map_err(|e| MyError::ReadFileError{file: source_path.display().to_string(), error: e})?;
+    let mut intervals: Vec<(String, ChrIntervals)> = intervals.into_iter().collect();
+    intervals.sort_by(|a, b| a.0.cmp(&b.0));
+    let cache = Cache{
+        source: source_path.display().to_string(),
+        source_crc32,
+        source_size,
+        description,
+        options,
+        stats,
+        intervals,
+    };
+    write_cache(&cache, &paras.output)?;
+    let n: usize = cache.intervals.iter().map(|(_, v)| v.len()).sum();
+    println!("{} intervals on {} chromosomes were written to {}", n, cache.intervals.len(), paras.output.display());
+    Ok(())
+}
+
+/// 计算文件的crc32和大小
+fn file_crc32(file: &Path) -> Result<(u32, u64), MyError> {
+    let mut f = File::open(file).map_err(|e| MyError::OpenFileError{file: file.display().to_string(), error: e})?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buf = vec![0u8; 1 << 16];
+    let mut size: u64 = 0;
+    loop {
+        let n = f.read(&mut buf).map_err(|e| MyError::ReadFileError{file: file.display().to_string(), error: e})?;
+        if n == 0 {
+            break
+        }
+        hasher.update(&buf[..n]);
+        size += n as u64;
+    }
+    Ok((hasher.finalize(), size))
+}
+
+/// 写入缓存文件
+pub fn write_cache(cache: &Cache, output: &Path) -> Result<(), MyError> {
+    let mut buf: Vec<u8> = Vec::new();
+    buf.extend_from_slice(CACHE_MAGIC);
+    buf.extend_from_slice(&cache.source_crc32.to_le_bytes());
+    buf.extend_from_slice(&cache.source_size.to_le_bytes());
+    put_str(&mut buf, &cache.source);
+    put_str(&mut buf, &cache.description);
+    buf.extend_from_slice(&(cache.options.len() as u32).to_le_bytes());
+    for (option, value) in &cache.options {
+        put_str(&mut buf, option);
+        put_str(&mut buf, value);
+    }
+    buf.extend_from_slice(&(cache.stats.skipped as u64).to_le_bytes());
+    buf.extend_from_slice(&(cache.stats.filtered as u64).to_le_bytes());
+    buf.extend_from_slice(&(cache.intervals.len() as u32).to_le_bytes());
+    for (chr, intervals) in &cache.intervals {
+        put_str(&mut buf, chr);
+        buf.extend_from_slice(&(intervals.len() as u32).to_le_bytes());
+        let mut sorted = intervals.clone();
+        sorted.sort_unstable_by_key(|(s, e, _)| (*s, *e));
+        let mut last_start: u64 = 0;
+        for (start, end, strand) in sorted {
+            put_varint(&mut buf, start - last_start);
+            put_varint(&mut buf, end.saturating_sub(start));
+            buf.push(match strand {
+                FeatureStrand::Forward => b'+',
+                FeatureStrand::Reverse => b'-',
+                FeatureStrand::Unknown => b'.',
+            });
+            last_start = start;
+        }
+    }
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+    fs::write(output, buf).map_err(|e| MyError::WriteFileError{file: output.display().to_string(), error: e})
+}
+
+/// 读取缓存文件，先检查末尾的crc32，避免使用不完整或损坏的缓存
+pub fn read_cache(file: &Path) -> Result<Cache, MyError> {
+    let data = fs::read(file).map_err(|e| MyError::ReadFileError{file: file.display().to_string(), error: e})?;
+    let cache_error = |reason: &str| MyError::ReadCacheError{file: file.display().to_string(), error: anyhow!("{}", reason)};
+    if data.len() < CACHE_MAGIC.len() + 4 || &data[..CACHE_MAGIC.len()] != CACHE_MAGIC {
+        return Err(cache_error("not an annotation cache, or built by an incompatible version"))
+    }
+    let (body, crc) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(crc.try_into().unwrap()) {
+        return Err(cache_error("checksum mismatch, the cache is truncated or corrupted"))
+    }
+    let mut r = ByteReader{data: body, pos: CACHE_MAGIC.len()};
+    let truncated = || cache_error("unexpected end of cache");
+    let source_crc32 = r.u32().ok_or_else(truncated)?;
+    let source_size = r.u64().ok_or_else(truncated)?;
+    let source = r.str().ok_or_else(truncated)?;
+    let description = r.str().ok_or_else(truncated)?;
+    let n_options = r.u32().ok_or_else(truncated)?;
+    let mut options: Vec<(String, String)> = Vec::new();
+    for _ in 0..n_options {
+        options.push((r.str().ok_or_else(truncated)?, r.str().ok_or_else(truncated)?));
+    }
+    let stats = LoadStats{
+        skipped: r.u64().ok_or_else(truncated)? as usize,
+        filtered: r.u64().ok_or_else(truncated)? as usize,
+        ..Default::default()
+    };
+    let n_chr = r.u32().ok_or_else(truncated)?;
+    let mut intervals: Vec<(String, ChrIntervals)> = Vec::with_capacity(n_chr as usize);
+    for _ in 0..n_chr {
+        let chr = r.str().ok_or_else(truncated)?;
+        let n = r.u32().ok_or_else(truncated)? as usize;
+        let mut chr_intervals: ChrIntervals = Vec::with_capacity(n);
+        let mut start: u64 = 0;
+        for _ in 0..n {
+            start += r.varint().ok_or_else(truncated)?;
+            let len = r.varint().ok_or_else(truncated)?;
+            let strand = match r.u8().ok_or_else(truncated)? {
+                b'+' => FeatureStrand::Forward,
+                b'-' => FeatureStrand::Reverse,
+                _ => FeatureStrand::Unknown,
+            };
+            chr_intervals.push((start, start + len, strand));
+        }
+        intervals.push((chr, chr_intervals));
+    }
+    Ok(Cache{source, source_crc32, source_size, description, options, stats, intervals})
+}
+
+/// 写入u32长度和字符串
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// 写入LEB128编码的无符号整数
+fn put_varint(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push((v as u8) | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+/// 按顺序读取缓存的字节，越界时返回None
+struct ByteReader<'a> {
+    data: &'a [u8],
+    pos:  usize,
+}
+
+impl ByteReader<'_> {
+    fn take(&mut self, n: usize) -> Option<&[u8]> {
+        let s = self.data.get(self.pos..self.pos + n)?;
+        self.pos += n;
+        Some(s)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4).map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8).map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let n = self.u32()? as usize;
+        self.take(n).map(|b| String::from_utf8_lossy(b).to_string())
+    }
+
+    fn varint(&mut self) -> Option<u64> {
+        let mut v: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.u8()?;
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Some(v)
+            }
+            shift += 7;
+            if shift >= 64 {
+                return None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_cache_{}_{}", std::process::id(), name))
+    }
+
+    fn sample_cache() -> Cache {
+        Cache{
+            source: "genes.gtf".to_string(),
+            source_crc32: 0xdeadbeef,
+            source_size: 123456789,
+            description: "format: gtf, feature: exon, gtf-attr: none, parse-mode: strict".to_string(),
+            options: vec![("-f".to_string(), "exon".to_string()), ("--gtf-attr".to_string(), String::new())],
+            stats: LoadStats{skipped: 3, filtered: 7, ..Default::default()},
+            intervals: vec![
+                ("chr1".to_string(), vec![
+                    (0, 0, FeatureStrand::Forward),                  // 长度为0
+                    (127, 128, FeatureStrand::Reverse),              // 差值刚好是1字节varint的上限
+                    (128, 16511, FeatureStrand::Unknown),            // 2字节varint
+                    (1 << 40, (1 << 40) + (1 << 35), FeatureStrand::Forward), // 多字节varint
+                    (u64::MAX >> 1, u64::MAX, FeatureStrand::Reverse), // 最大的差值和长度
+                ]),
+                ("chrEmpty".to_string(), Vec::new()),
+            ],
+        }
+    }
+
+    #[test]
+    fn cache_round_trip() {
+        let file = temp_file("round_trip");
+        let cache = sample_cache();
+        write_cache(&cache, &file).unwrap();
+        let read = read_cache(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(read.source, cache.source);
+        assert_eq!(read.source_crc32, cache.source_crc32);
+        assert_eq!(read.source_size, cache.source_size);
+        assert_eq!(read.description, cache.description);
+        assert_eq!(read.options, cache.options);
+        assert_eq!((read.stats.skipped, read.stats.filtered), (3, 7));
+        assert_eq!(read.intervals, cache.intervals);
+    }
+
+    #[test]
+    fn check_source_changes() {
+        let source = temp_file("source.gtf");
+        fs::write(&source, "chr1\tsrc\tgene\t1\t100\t.\t+\t.\tgene_id \"g1\";\n").unwrap();
+        let (source_crc32, source_size) = file_crc32(&source).unwrap();
+        let cache = Cache{source: source.display().to_string(), source_crc32, source_size, ..sample_cache()};
+        let checker = |verify_source| CacheSource{path: temp_file("unused.idx"), expected: Vec::new(), verify_source};
+        assert!(checker(true).check_source(&cache).is_ok());
+        // 内容改变
+        fs::write(&source, "chr1\tsrc\tgene\t1\t200\t.\t+\t.\tgene_id \"g1\";\n").unwrap();
+        let err = checker(true).check_source(&cache).err().unwrap().to_string();
+        assert!(err.contains("changed since the cache was built"), "{}", err);
+        assert!(checker(false).check_source(&cache).is_ok());
+        // 源注释不存在时无法检查
+        fs::remove_file(&source).unwrap();
+        let err = checker(true).check_source(&cache).err().unwrap().to_string();
+        assert!(err.contains("not found, cannot verify"), "{}", err);
+        assert!(checker(false).check_source(&cache).is_ok());
+    }
+
+    #[test]
+    fn varint_round_trip() {
+        for v in [0, 1, 127, 128, 300, 16383, 16384, u32::MAX as u64, u64::MAX - 1, u64::MAX] {
+            let mut buf = Vec::new();
+            put_varint(&mut buf, v);
+            let mut r = ByteReader{data: &buf, pos: 0};
+            assert_eq!(r.varint(), Some(v));
+            assert_eq!(r.pos, buf.len());
+        }
+        // 没有结束字节
+        assert_eq!(ByteReader{data: &[0x80, 0x80], pos: 0}.varint(), None);
+    }
+
+    #[test]
+    fn corrupted_cache_is_rejected() {
+        let file = temp_file("corrupted");
+        write_cache(&sample_cache(), &file).unwrap();
+        let data = fs::read(&file).unwrap();
+        // 修改中间的一个字节，crc32不一致
+        let mut flipped = data.clone();
+        flipped[data.len() / 2] ^= 0x01;
+        fs::write(&file, &flipped).unwrap();
+        let err = read_cache(&file).err().unwrap().to_string();
+        assert!(err.contains("checksum mismatch"), "{}", err);
+        // 截断
+        fs::write(&file, &data[..data.len() - 10]).unwrap();
+        assert!(read_cache(&file).is_err());
+        // 旧版本或其他文件
+        let mut old = data.clone();
+        old[7] = 0x01;
+        fs::write(&file, &old).unwrap();
+        let err = read_cache(&file).err().unwrap().to_string();
+        assert!(err.contains("incompatible version"), "{}", err);
+        fs::remove_file(&file).unwrap();
+    }
+}
@@ -0,0 +1,332 @@
+use std::collections::{HashMap, HashSet};
+use std::io::BufRead;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{
+    annotation::GeneRanges,
+    error::MyError,
+    utils::my_reader,
+};
+
+/// bam和注释可能来自不同基因组版本（比如hg19的bam用了hg38的注释）时的处理方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AssemblyCheck {
+    Off,   // 不检查
+    Warn,  // 打印警告，继续统计
+    Error, // 直接报错
+}
+
+impl FromStr for AssemblyCheck {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(AssemblyCheck::Off),
+            "warn" => Ok(AssemblyCheck::Warn),
+            "error" => Ok(AssemblyCheck::Error),
+            _ => Err(MyError::ParaError{para: format!("--assembly-check only support off, warn or error, not {}", s)}),
+        }
+    }
+}
+
+/// 比较注释和bam header判断是否来自不同基因组版本，返回发现的问题
+/// 1. feature超出了bam header中contig的长度（@SQ LN）
+/// 2. 一半以上的feature所在contig不在bam header中（已经按别名转换过）
+pub fn check_assembly(gene_ranges: &GeneRanges, lengths: &HashMap<String, u64>) -> Vec<String> {
+    let mut issues: Vec<String> = Vec::new();
+    let mut beyond: Vec<(&String, u64, u64)> = gene_ranges.max_end.iter()
+        .filter_map(|(chr, &end)| lengths.get(chr).filter(|&&len| len > 0 && end > len).map(|&len| (chr, end, len)))
+        .collect();
+    if !beyond.is_empty() {
+        beyond.sort();
+        issues.push(format!(
+            "features extend beyond the end of {} contigs: {}",
+            beyond.len(),
+            preview(beyond.iter().map(|(chr, end, len)| format!("{} (annotation up to {}, BAM length {})", chr, end, len)).collect()),
+        ));
+    }
+    let unused: usize = gene_ranges.unused_contigs.values().sum();
+    if unused > gene_ranges.features {
+        issues.push(format!(
+            "{} of {} annotation features are on contigs absent from the BAM header",
+            unused,
+            unused + gene_ranges.features,
+        ));
+    }
+    issues
+}
+
+/// 按--assembly-check处理check_assembly发现的问题，error时返回AssemblyMismatchError，warn时返回需要打印的警告，off时不检查
+pub fn assembly_warnings(gene_ranges: &GeneRanges, lengths: &HashMap<String, u64>, check: AssemblyCheck, file: &Path) -> Result<Vec<String>, MyError> {
+    if check == AssemblyCheck::Off {
+        return Ok(Vec::new())
+    }
+    let issues = check_assembly(gene_ranges, lengths);
+    if check == AssemblyCheck::Error && !issues.is_empty() {
+        return Err(MyError::AssemblyMismatchError{file: file.display().to_string(), detail: issues.join("; ")})
+    }
+    Ok(issues.into_iter().map(|issue| format!("Warning - BAM and annotation may be from different assemblies, {}", issue)).collect())
+}
+
+/// bam header中的contig，读取注释时跳过其他contig上的feature，比如alt单倍型、patch、合并gtf中的其他物种
+/// 注释和bam的contig命名不一致时（比如"chr1"和"1"、"chrM"和"MT"、RefSeq的"NC_000001.11"），自动转换为bam中的名称
+/// RefSeq accession只转换与bam同一物种（根据@SQ中1号染色体的长度判断）的，比如人和小鼠合并的gtf中小鼠的accession不会对应到人的染色体
+/// 还可以通过别名表（NCBI assembly report、UCSC chromAlias或两列的tsv）指定其他别名
+#[derive(Debug, Clone, Default)]
+pub struct ContigFilter {
+    names:     HashSet<String>,         // bam header中的contig
+    canonical: HashMap<String, String>, // 规范化的名称 -> bam中的名称
+    aliases:   HashMap<String, String>, // 别名表中的名称 -> bam中的名称
+    species:   Option<RefSeqSpecies>,   // bam的物种，无法判断时不自动转换RefSeq accession
+}
+
+impl ContigFilter {
+    /// names和lengths为bam header中的contig名称和长度
+    pub fn new(names: &[String], lengths: &[u64]) -> Self {
+        let species = RefSeqSpecies::from_header(names, lengths);
+        let names: HashSet<String> = names.iter().cloned().collect();
+        let mut canonical: HashMap<String, String> = HashMap::new();
+        for n in &names {
+            // bam中同时有"chrM"和"MT"这种情况很少，保留第一个
+            canonical.entry(canonical_name(n, species)).or_insert(n.clone());
+        }
+        ContigFilter{names, canonical, aliases: HashMap::new(), species}
+    }
+
+    /// 添加别名，每组中的名称互为别名，组内有bam中的名称（或能自动转换为bam中的名称）时，其他名称都对应到它
+    pub fn add_aliases(&mut self, groups: Vec<Vec<String>>) {
+        for group in groups {
+            let target = group.iter().find(|n| self.names.contains(n.as_str())).cloned()
+                .or_else(|| group.iter().find_map(|n| self.canonical.get(&canonical_name(n, self.species)).cloned()));
+            if let Some(target) = target {
+                for n in group {
+                    if n != target {
+                        self.aliases.insert(n, target.clone());
+                    }
+                }
+            }
+        }
+    }
+
+    /// 注释中的contig对应的bam中的名称，依次尝试完全一致、别名表、自动转换
+    pub fn resolve(&self, chr: &str) -> Option<&str> {
+        if let Some(n) = self.names.get(chr) {
+            return Some(n)
+        }
+        if let Some(n) = self.aliases.get(chr) {
+            return Some(n)
+        }
+        self.canonical.get(&canonical_name(chr, self.species)).map(|n| n.as_str())
+    }
+
+    pub fn contains(&self, chr: &str) -> bool {
+        self.resolve(chr).is_some()
+    }
+}
+
+/// contig可能很多（比如patch、scaffold），打印时只列出前10个
+pub fn preview(names: Vec<String>) -> String {
+    let n = names.len();
+    let mut names: Vec<String> = names.into_iter().take(10).collect();
+    if n > 10 {
+        names.push("...".to_string());
+    }
+    names.join(", ")
+}
+
+/// 规范化contig名称，去掉"chr"前缀，线粒体统一为"MT"，species物种的RefSeq染色体accession转为染色体编号
+fn canonical_name(name: &str, species: Option<RefSeqSpecies>) -> String {
+    if let Some(c) = species.and_then(|s| s.chromosome(refseq_number(name)?)) {
+        return c
+    }
+    match strip_chr(name) {
+        "M" | "MT" | "Mt" => "MT".to_string(),
+        n => n.to_string(),
+    }
+}
+
+/// 去掉"chr"前缀
+fn strip_chr(name: &str) -> &str {
+    ["chr", "Chr", "CHR"].iter().find_map(|p| name.strip_prefix(p)).unwrap_or(name)
+}
+
+/// RefSeq accession的编号，忽略版本号，比如NC_000001.10和NC_000001.11都是1
+fn refseq_number(name: &str) -> Option<u32> {
+    name.split('.').next()?.strip_prefix("NC_")?.parse().ok()
+}
+
+/// 可以自动转换RefSeq染色体accession的物种，人和小鼠的accession转换后都是"1"、"X"、"MT"这样的名称，所以每次只转换一个物种的
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RefSeqSpecies {
+    Human, // GRCh37/GRCh38，NC_000001-NC_000024、NC_012920
+    Mouse, // GRCm38/GRCm39，NC_000067-NC_000087、NC_005089
+}
+
+impl RefSeqSpecies {
+    /// 根据bam header中1号染色体（"1"、"chr1"或对应的accession）的长度判断物种
+    /// 人：GRCh37 249250621、GRCh38 248956422，小鼠：GRCm38 195471971、GRCm39 195154279
+    fn from_header(names: &[String], lengths: &[u64]) -> Option<Self> {
+        names.iter().zip(lengths).find_map(|(name, len)| {
+            let is_chr1 = |accession: u32| strip_chr(name) == "1" || refseq_number(name) == Some(accession);
+            match len {
+                249250621 | 248956422 if is_chr1(1) => Some(RefSeqSpecies::Human),
+                195471971 | 195154279 if is_chr1(67) => Some(RefSeqSpecies::Mouse),
+                _ => None,
+            }
+        })
+    }
+
+    /// accession编号对应的染色体，不是该物种的accession时返回None
+    fn chromosome(&self, num: u32) -> Option<String> {
+        match (self, num) {
+            (RefSeqSpecies::Human, 1..=22) => Some(num.to_string()),
+            (RefSeqSpecies::Human, 23) => Some("X".to_string()),
+            (RefSeqSpecies::Human, 24) => Some("Y".to_string()),
+            (RefSeqSpecies::Human, 12920) => Some("MT".to_string()),
+            (RefSeqSpecies::Mouse, 67..=85) => Some((num - 66).to_string()),
+            (RefSeqSpecies::Mouse, 86) => Some("X".to_string()),
+            (RefSeqSpecies::Mouse, 87) => Some("Y".to_string()),
+            (RefSeqSpecies::Mouse, 5089) => Some("MT".to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// 读取别名表，返回每行的一组别名
+/// NCBI assembly report（#开头的注释行，之后至少10列）取Sequence-Name、GenBank-Accn、RefSeq-Accn、UCSC-style-name，忽略"na"
+/// 其他格式（两列的tsv、UCSC chromAlias.txt）每行所有列互为别名
+pub fn read_alias_table(file: &Path) -> Result<Vec<Vec<String>>, MyError> {
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    for line in my_reader(file, 1)?.lines() {
+        let line = line.map_err(|e| MyError::LinesError{file: file.display().to_string(), error: e})?;
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue
+        }
+        let fields: Vec<&str> = line.split('\t').map(|f| f.trim()).collect();
+        let names: Vec<&str> = if fields.len() >= 10 {
+            vec![fields[0], fields[4], fields[6], fields[9]]
+        } else {
+            fields
+        };
+        let group: Vec<String> = names.into_iter().filter(|n| !n.is_empty() && *n != "na").map(|n| n.to_string()).collect();
+        if group.len() > 1 {
+            groups.push(group);
+        }
+    }
+    Ok(groups)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{
+        annotation::{AnnotationSource, FeatureStrand, GeneModel, LoadStats, UnstrandedMode},
+        error::MyError,
+    };
+
+    /// 内存中的基因模型，按AnnotationSource的约定跳过不在contigs中的feature
+    struct ModelSource(Vec<GeneModel>);
+
+    impl AnnotationSource for ModelSource {
+        fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+            let mut stats = LoadStats::default();
+            for m in &self.0 {
+                if stats.keep_contig(contigs, &m.chr) {
+                    sink(m.clone());
+                }
+            }
+            Ok(stats)
+        }
+    }
+
+    fn model(chr: &str, start: u64, end: u64) -> GeneModel {
+        GeneModel{chr: chr.to_string(), start, end, strand: FeatureStrand::Forward, name: None, exons: None}
+    }
+
+    /// 按bam header加载注释
+    fn load(names: &[&str], lengths: &[u64], models: Vec<GeneModel>) -> (GeneRanges, HashMap<String, u64>) {
+        let contigs = filter(names, lengths);
+        let ranges = GeneRanges::from_source(&ModelSource(models), UnstrandedMode::Separate, false, Some(&contigs)).unwrap();
+        (ranges, names.iter().map(|n| n.to_string()).zip(lengths.iter().copied()).collect())
+    }
+
+    fn filter(names: &[&str], lengths: &[u64]) -> ContigFilter {
+        ContigFilter::new(&names.iter().map(|n| n.to_string()).collect::<Vec<_>>(), lengths)
+    }
+
+    #[test]
+    fn refseq_only_for_bam_species() {
+        // GRCh38的bam，人和小鼠合并的注释
+        let human = filter(&["chr1", "chr2", "chrX", "chrM"], &[248956422, 242193529, 156040895, 16569]);
+        assert_eq!(human.resolve("NC_000001.11"), Some("chr1"));
+        assert_eq!(human.resolve("NC_000023.11"), Some("chrX"));
+        assert_eq!(human.resolve("NC_012920.1"), Some("chrM"));
+        assert_eq!(human.resolve("NC_000067.7"), None);
+        assert_eq!(human.resolve("NC_000086.8"), None);
+        assert_eq!(human.resolve("NC_005089.1"), None);
+        // GRCm39的bam
+        let mouse = filter(&["1", "2", "X", "MT"], &[195154279, 181755017, 169476592, 16299]);
+        assert_eq!(mouse.resolve("NC_000067.7"), Some("1"));
+        assert_eq!(mouse.resolve("NC_000086.8"), Some("X"));
+        assert_eq!(mouse.resolve("NC_005089.1"), Some("MT"));
+        assert_eq!(mouse.resolve("NC_000001.11"), None);
+        assert_eq!(mouse.resolve("chr1"), Some("1"));
+        // bam本身使用accession
+        let accession = filter(&["NC_000001.10", "NC_012920.1"], &[249250621, 16569]);
+        assert_eq!(accession.resolve("chr1"), Some("NC_000001.10"));
+        assert_eq!(accession.resolve("chrM"), Some("NC_012920.1"));
+    }
+
+    #[test]
+    fn unknown_species_needs_alias_table() {
+        let other = filter(&["chr1", "chrM"], &[1000, 16569]);
+        assert_eq!(other.resolve("NC_000001.11"), None);
+        assert_eq!(other.resolve("1"), Some("chr1"));
+        assert_eq!(other.resolve("MT"), Some("chrM"));
+        let mut aliased = other.clone();
+        aliased.add_aliases(vec![vec!["NC_000001.11".to_string(), "1".to_string()]]);
+        assert_eq!(aliased.resolve("NC_000001.11"), Some("chr1"));
+    }
+
+    #[test]
+    fn grch38_annotation_on_grch37_bam() {
+        // GRCh37的@SQ长度，GRCh38的注释：chr1、chr2在GRCh38中更短，chrX的最后一个feature超出了GRCh37的长度
+        let (ranges, lengths) = load(
+            &["chr1", "chr2", "chrX"],
+            &[249250621, 243199373, 155270560],
+            vec![model("chr1", 248900000, 248950000), model("chr2", 242100000, 242190000), model("chrX", 155500000, 155600000)],
+        );
+        let issue = "features extend beyond the end of 1 contigs: chrX (annotation up to 155600000, BAM length 155270560)";
+        assert_eq!(check_assembly(&ranges, &lengths), vec![issue]);
+        let file = Path::new("sample.bam");
+        match assembly_warnings(&ranges, &lengths, AssemblyCheck::Error, file) {
+            Err(e @ MyError::AssemblyMismatchError{..}) => assert_eq!(e.to_string(), format!("Error - sample.bam and the annotation look like different assemblies: {}", issue)),
+            other => panic!("expected AssemblyMismatchError, got {:?}", other),
+        }
+        assert_eq!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Warn, file).unwrap(), vec![format!("Warning - BAM and annotation may be from different assemblies, {}", issue)]);
+        assert!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Off, file).unwrap().is_empty());
+        // 不超出长度时没有问题
+        let (ranges, lengths) = load(&["chrX"], &[156040895], vec![model("chrX", 155500000, 155600000)]);
+        assert!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Error, file).unwrap().is_empty());
+    }
+
+    #[test]
+    fn most_features_on_absent_contigs() {
+        let file = Path::new("sample.bam");
+        // 3个feature在bam中没有的contig上，只加载了1个
+        let (ranges, lengths) = load(
+            &["chr1"],
+            &[248956422],
+            vec![model("chr1", 100, 200), model("chrUn_KI270302v1", 100, 200), model("chrUn_KI270304v1", 100, 200), model("HLA-A*01:01:01:01", 100, 200)],
+        );
+        assert_eq!((ranges.features, ranges.unused_contigs.len()), (1, 3));
+        let issue = "3 of 4 annotation features are on contigs absent from the BAM header";
+        assert!(matches!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Error, file), Err(MyError::AssemblyMismatchError{detail, ..}) if detail == issue));
+        assert_eq!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Warn, file).unwrap(), vec![format!("Warning - BAM and annotation may be from different assemblies, {}", issue)]);
+        // 不超过一半时不报告
+        let (ranges, lengths) = load(&["chr1"], &[248956422], vec![model("chr1", 100, 200), model("chrUn_KI270302v1", 100, 200)]);
+        assert!(assembly_warnings(&ranges, &lengths, AssemblyCheck::Error, file).unwrap().is_empty());
+    }
+}
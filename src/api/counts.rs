@@ -0,0 +1,374 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::BufRead;
+use std::path::Path;
+
+use anyhow::{anyhow, bail};
+
+use crate::{
+    annotation::UnstrandedMode,
+    error::MyError,
+    infer::report_counts,
+    parse_paras::MergeParas,
+    utils::my_reader,
+};
+
+/// read比对到的所有feature的链信息集合，每种链信息占1个bit，共8种组合
+pub const STRAND_SETS: usize = 8;
+
+/// 只比对到某一种链信息feature的集合
+pub const ONLY_FORWARD: usize = 1;
+pub const ONLY_REVERSE: usize = 2;
+pub const ONLY_UNKNOWN: usize = 4;
+
+/// 统计结果，按(mate, read比对的链, feature链信息集合)计数，统计时不需要分配内存
+/// 下标：mate 0为first、1为last，read比对的链 0为+、1为-，feature链信息集合为FeatureStrand::bit的按位或
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StrandCounts {
+    pub paired: [[[u64; STRAND_SETS]; 2]; 2], // 双端read，[mate][read strand][feature strand set]
+    pub single: [[u64; STRAND_SETS]; 2],      // 单端read，[read strand][feature strand set]
+}
+
+impl StrandCounts {
+    /// 记录一条双端read
+    pub fn add_paired(&mut self, first: bool, reverse: bool, strands: u8) {
+        self.paired[!first as usize][reverse as usize][strands as usize] += 1;
+    }
+
+    /// 记录一条单端read
+    pub fn add_single(&mut self, reverse: bool, strands: u8) {
+        self.single[reverse as usize][strands as usize] += 1;
+    }
+
+    /// 双端read总数
+    pub fn paired_sum(&self) -> u64 {
+        self.paired.iter().flatten().flatten().sum()
+    }
+
+    /// 单端read总数
+    pub fn single_sum(&self) -> u64 {
+        self.single.iter().flatten().sum()
+    }
+
+    /// 合并另一份统计
+    pub fn merge(&mut self, other: &StrandCounts) {
+        for (a, b) in self.paired.iter_mut().flatten().flatten().zip(other.paired.iter().flatten().flatten()) {
+            *a += b;
+        }
+        for (a, b) in self.single.iter_mut().flatten().zip(other.single.iter().flatten()) {
+            *a += b;
+        }
+    }
+
+    /// 双端和单端合计，分别能被两种链特异性解释的read数量
+    /// 第1种: 1++,1--,2+-,2-+ 以及 ++,--，第2种: 1+-,1-+,2++,2-- 以及 +-,-+
+    pub fn explained(&self) -> (u64, u64) {
+        let p = &self.paired;
+        let s = &self.single;
+        (
+            p[0][0][ONLY_FORWARD] + p[0][1][ONLY_REVERSE] + p[1][0][ONLY_REVERSE] + p[1][1][ONLY_FORWARD] + s[0][ONLY_FORWARD] + s[1][ONLY_REVERSE],
+            p[0][0][ONLY_REVERSE] + p[0][1][ONLY_FORWARD] + p[1][0][ONLY_FORWARD] + p[1][1][ONLY_REVERSE] + s[0][ONLY_REVERSE] + s[1][ONLY_FORWARD],
+        )
+    }
+}
+
+/// 一次扫描的统计结果，按contig（bam的tid）分别计数，多线程时每个线程一份，最后合并
+#[derive(Debug, Clone, Default)]
+pub struct ReadStats {
+    pub contigs:      Vec<StrandCounts>, // 每个contig的统计，下标为tid
+    pub count:        usize,             // usable read数量
+    pub unknown_mate: usize,             // 既不是first也不是last的双端read数量
+    pub records:      usize,             // 读取的record数量，包括被过滤的
+    pub budget_hit:   bool,              // 是否因为--max-records或--max-seconds提前停止，此时结果不完整
+    pub genes:        BTreeMap<(usize, u32), StrandCounts>, // 每个基因（tid、locus）的统计，只有按基因bootstrap时才记录，不写入统计结果文件
+}
+
+impl ReadStats {
+    pub fn new(n_contigs: usize) -> Self {
+        ReadStats{contigs: vec![StrandCounts::default(); n_contigs], count: 0, unknown_mate: 0, records: 0, budget_hit: false, genes: BTreeMap::new()}
+    }
+
+    /// 合并另一份统计，两份统计的contig需要一致
+    pub fn merge(&mut self, other: &ReadStats) {
+        for (a, b) in self.contigs.iter_mut().zip(&other.contigs) {
+            a.merge(b);
+        }
+        for (gene, b) in &other.genes {
+            self.genes.entry(*gene).or_default().merge(b);
+        }
+        self.count += other.count;
+        self.unknown_mate += other.unknown_mate;
+        self.records += other.records;
+        self.budget_hit |= other.budget_hit;
+    }
+
+    /// 所有contig合计
+    pub fn total(&self) -> StrandCounts {
+        let mut total = StrandCounts::default();
+        for c in &self.contigs {
+            total.merge(c);
+        }
+        total
+    }
+}
+
+/// 统计结果文件第一行，最后是格式版本
+pub const COUNTS_MAGIC: &str = "#infer_experiment counts v1";
+
+/// feature链信息集合在统计结果文件列名中的写法，下标为FeatureStrand::bit的按位或，F: +，R: -，U: 无链信息，N: 没有feature
+const STRAND_SET_NAMES: [&str; STRAND_SETS] = ["N", "F", "R", "FR", "U", "FU", "RU", "FRU"];
+
+/// 可以写入文件并合并的统计结果，按区域拆分bam分别统计（--counts-out），再用merge子命令合并，和对所有read统计一次的结果一致
+/// 文件为tsv格式：
+/// 第一行为COUNTS_MAGIC，之后#开头的行为"#key<TAB>value"：usable_reads、unknown_mate、records、budget_hit、unstranded、unknown_mate_as_single
+/// 然后是列名行，之后每个contig一行：contig名称，32列双端计数（列名如"1+F"：mate、read比对的链、feature链信息集合），16列单端计数（列名如"+F"）
+#[derive(Debug, Clone)]
+pub struct CountAccumulator {
+    pub contigs:    Vec<String>,    // bam header中的contig，与stats.contigs一一对应
+    pub stats:      ReadStats,      // 统计结果
+    pub unstranded: UnstrandedMode, // 无链信息feature的处理方式，不同方式的统计不能合并
+    pub unknown_mate_as_single: bool, // 既不是first也不是last的双端read是否当作单端read，不同方式的统计不能合并
+}
+
+impl CountAccumulator {
+    /// 列名
+    fn columns() -> Vec<String> {
+        let mut columns: Vec<String> = vec!["contig".to_string()];
+        for mate in ["1", "2"] {
+            for strand in ["+", "-"] {
+                columns.extend(STRAND_SET_NAMES.iter().map(|set| format!("{}{}{}", mate, strand, set)));
+            }
+        }
+        for strand in ["+", "-"] {
+            columns.extend(STRAND_SET_NAMES.iter().map(|set| format!("{}{}", strand, set)));
+        }
+        columns
+    }
+
+    /// 写入统计结果文件
+    pub fn write(&self, output: &Path) -> Result<(), MyError> {
+        let mut out: Vec<String> = vec![
+            COUNTS_MAGIC.to_string(),
+            format!("#usable_reads\t{}", self.stats.count),
+            format!("#unknown_mate\t{}", self.stats.unknown_mate),
+            format!("#records\t{}", self.stats.records),
+            format!("#budget_hit\t{}", self.stats.budget_hit),
+            format!("#unstranded\t{}", self.unstranded),
+            format!("#unknown_mate_as_single\t{}", self.unknown_mate_as_single),
+            Self::columns().join("\t"),
+        ];
+        for (chr, counts) in self.contigs.iter().zip(&self.stats.contigs) {
+            let values: Vec<String> = counts.paired.iter().flatten().flatten().chain(counts.single.iter().flatten()).map(|v| v.to_string()).collect();
+            out.push(format!("{}\t{}", chr, values.join("\t")));
+        }
+        fs::write(output, out.join("\n") + "\n").map_err(|e| MyError::WriteFileError{file: output.display().to_string(), error: e})
+    }
+
+    /// 读取统计结果文件
+    pub fn read(file: &Path) -> Result<Self, MyError> {
+        Self::parse(file).map_err(|e| MyError::ReadCountsError{file: file.display().to_string(), error: e})
+    }
+
+    fn parse(file: &Path) -> anyhow::Result<Self> {
+        let columns = Self::columns();
+        let mut lines = my_reader(file, 1)?.lines().enumerate();
+        match lines.next() {
+            Some((_, Ok(l))) if l.trim_end() == COUNTS_MAGIC => (),
+            _ => bail!("not a counts file written by --counts-out (first line should be \"{}\")", COUNTS_MAGIC),
+        }
+        let mut acc = CountAccumulator{contigs: Vec::new(), stats: ReadStats::default(), unstranded: UnstrandedMode::Separate, unknown_mate_as_single: false};
+        let mut header = false;
+        for (i, line) in lines {
+            let line = line?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                continue
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if let Some(key) = fields[0].strip_prefix('#') {
+                let value = fields.get(1).ok_or_else(|| anyhow!("line {}: missing value of {}", i + 1, key))?;
+                match key {
+                    "usable_reads" => acc.stats.count = value.parse().map_err(|e| anyhow!("line {}: {}", i + 1, e))?,
+                    "unknown_mate" => acc.stats.unknown_mate = value.parse().map_err(|e| anyhow!("line {}: {}", i + 1, e))?,
+                    "records" => acc.stats.records = value.parse().map_err(|e| anyhow!("line {}: {}", i + 1, e))?,
+                    "budget_hit" => acc.stats.budget_hit = value.parse().map_err(|e| anyhow!("line {}: {}", i + 1, e))?,
+                    "unstranded" => acc.unstranded = value.parse()?,
+                    "unknown_mate_as_single" => acc.unknown_mate_as_single = value.parse().map_err(|e| anyhow!("line {}: {}", i + 1, e))?,
+                    _ => (), // 忽略不认识的key，方便以后添加
+                }
+                continue
+            }
+            if !header {
+                if fields != columns {
+                    bail!("line {}: unexpected column names", i + 1)
+                }
+                header = true;
+                continue
+            }
+            if fields.len() != columns.len() {
+                bail!("line {}: expected {} columns, found {}", i + 1, columns.len(), fields.len())
+            }
+            let values: Vec<u64> = fields[1..].iter().map(|v| v.parse::<u64>()).collect::<Result<_, _>>().map_err(|e| anyhow!("line {}: {}", i + 1, e))?;
+            let mut counts = StrandCounts::default();
+            for (a, b) in counts.paired.iter_mut().flatten().flatten().chain(counts.single.iter_mut().flatten()).zip(values) {
+                *a = b;
+            }
+            acc.contigs.push(fields[0].to_string());
+            acc.stats.contigs.push(counts);
+        }
+        if !header {
+            bail!("missing column names")
+        }
+        Ok(acc)
+    }
+
+    /// 合并另一份统计，contig按名称对应，没有的contig添加到最后
+    pub fn merge(&mut self, other: &CountAccumulator) -> anyhow::Result<()> {
+        if self.unstranded != other.unstranded {
+            bail!("counted with -u {}, but the previous files used -u {}", other.unstranded, self.unstranded)
+        }
+        if self.unknown_mate_as_single != other.unknown_mate_as_single {
+            bail!("--unknown-mate-as-single differs from the previous files")
+        }
+        let mut index: HashMap<String, usize> = self.contigs.iter().enumerate().map(|(i, c)| (c.clone(), i)).collect();
+        for (chr, counts) in other.contigs.iter().zip(&other.stats.contigs) {
+            let i = *index.entry(chr.clone()).or_insert_with(|| {
+                self.contigs.push(chr.clone());
+                self.stats.contigs.push(StrandCounts::default());
+                self.contigs.len() - 1
+            });
+            self.stats.contigs[i].merge(counts);
+        }
+        self.stats.count += other.stats.count;
+        self.stats.unknown_mate += other.stats.unknown_mate;
+        self.stats.records += other.stats.records;
+        self.stats.budget_hit |= other.stats.budget_hit;
+        Ok(())
+    }
+}
+
+/// merge子命令，合并多个--counts-out输出的统计结果，打印与单次统计相同的结果
+pub fn run_merge(paras: &MergeParas) -> Result<(), MyError> {
+    let mut merged: Option<CountAccumulator> = None;
+    for file in &paras.inputs {
+        let acc = CountAccumulator::read(file)?;
+        match merged.as_mut() {
+            Some(m) => m.merge(&acc).map_err(|e| MyError::ReadCountsError{file: file.display().to_string(), error: e})?,
+            None => merged = Some(acc),
+        }
+    }
+    let merged = match merged {
+        Some(m) => m,
+        None => return Err(MyError::ParaError{para: "merge needs at least one counts file".to_string()}),
+    };
+    println!("Merged {} counts files", paras.inputs.len());
+    if let Some(output) = &paras.output {
+        merged.write(output)?;
+    }
+    report_counts(&merged, paras.layout, paras.per_contig, paras.bootstrap);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::PathBuf;
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_counts_{}_{}", std::process::id(), name))
+    }
+
+    /// 一条模拟的read：contig、是否双端、是否first、是否比对到-链、feature链信息集合
+    type Read = (usize, bool, bool, bool, u8);
+
+    /// 确定性的模拟read，覆盖所有计数
+    fn sample_reads(n: usize, n_contigs: usize) -> Vec<Read> {
+        let mut x: u64 = 0x9E37_79B9_7F4A_7C15;
+        (0..n).map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            ((x % n_contigs as u64) as usize, x >> 8 & 1 == 1, x >> 9 & 1 == 1, x >> 10 & 1 == 1, (x >> 11 & 7) as u8)
+        }).collect()
+    }
+
+    fn accumulate(contigs: &[&str], reads: &[Read]) -> CountAccumulator {
+        let mut stats = ReadStats::new(contigs.len());
+        for &(tid, paired, first, reverse, strands) in reads {
+            if paired {
+                stats.contigs[tid].add_paired(first, reverse, strands);
+            } else {
+                stats.contigs[tid].add_single(reverse, strands);
+            }
+            stats.count += 1;
+            stats.records += 2;
+        }
+        CountAccumulator{contigs: contigs.iter().map(|c| c.to_string()).collect(), stats, unstranded: UnstrandedMode::Separate, unknown_mate_as_single: false}
+    }
+
+    /// 按contig名称取统计
+    fn by_name(acc: &CountAccumulator) -> HashMap<String, StrandCounts> {
+        acc.contigs.iter().cloned().zip(acc.stats.contigs.iter().cloned()).collect()
+    }
+
+    #[test]
+    fn write_read_round_trip() {
+        let mut acc = accumulate(&["chr1", "chr2", "chrUn_KI270302v1", "chrM"], &sample_reads(5000, 4));
+        acc.stats.contigs[0].paired[1][1][7] = u64::MAX;
+        acc.stats.unknown_mate = 17;
+        acc.stats.budget_hit = true;
+        acc.unstranded = UnstrandedMode::Skip;
+        acc.unknown_mate_as_single = true;
+        let file = temp_file("round_trip.tsv");
+        acc.write(&file).unwrap();
+        let read = CountAccumulator::read(&file).unwrap();
+        fs::remove_file(&file).unwrap();
+        assert_eq!(read.contigs, acc.contigs);
+        assert_eq!(read.stats.contigs, acc.stats.contigs);
+        assert_eq!((read.stats.count, read.stats.unknown_mate, read.stats.records, read.stats.budget_hit), (acc.stats.count, 17, acc.stats.records, true));
+        assert_eq!((read.unstranded, read.unknown_mate_as_single), (UnstrandedMode::Skip, true));
+    }
+
+    #[test]
+    fn merged_shards_match_unsharded() {
+        let contigs = ["chr1", "chr2", "chr3", "chrX"];
+        let reads = sample_reads(20000, contigs.len());
+        let expected = accumulate(&contigs, &reads);
+        // 按read拆成几份分别统计，写入文件后再读取合并；最后两份只有部分contig，顺序也和bam header不同
+        let mut shards: Vec<(Vec<&str>, Vec<Read>)> = (0..2).map(|s| (contigs.to_vec(), reads.iter().skip(s).step_by(3).copied().collect())).collect();
+        let rest: Vec<Read> = reads.iter().skip(2).step_by(3).copied().collect();
+        for subset in [["chrX", "chr1"], ["chr3", "chr2"]] {
+            let tids: Vec<usize> = subset.iter().map(|c| contigs.iter().position(|x| x == c).unwrap()).collect();
+            let part: Vec<Read> = rest.iter().filter_map(|&(tid, p, f, r, s)| tids.iter().position(|&t| t == tid).map(|i| (i, p, f, r, s))).collect();
+            shards.push((subset.to_vec(), part));
+        }
+        let mut merged: Option<CountAccumulator> = None;
+        for (i, (names, part)) in shards.iter().enumerate() {
+            let file = temp_file(&format!("shard{}.tsv", i));
+            accumulate(names, part).write(&file).unwrap();
+            let acc = CountAccumulator::read(&file).unwrap();
+            fs::remove_file(&file).unwrap();
+            match merged.as_mut() {
+                Some(m) => m.merge(&acc).unwrap(),
+                None => merged = Some(acc),
+            }
+        }
+        let merged = merged.unwrap();
+        assert_eq!(by_name(&merged), by_name(&expected));
+        assert_eq!(merged.stats.total(), expected.stats.total());
+        assert_eq!((merged.stats.count, merged.stats.records), (expected.stats.count, expected.stats.records));
+    }
+
+    #[test]
+    fn merge_rejects_different_modes() {
+        let mut a = accumulate(&["chr1"], &sample_reads(10, 1));
+        let mut b = a.clone();
+        b.unstranded = UnstrandedMode::Skip;
+        assert!(a.merge(&b).is_err());
+        b.unstranded = a.unstranded;
+        b.unknown_mate_as_single = true;
+        assert!(a.merge(&b).is_err());
+    }
+}
@@ -2,6 +2,7 @@ use std::io;
 use std::num::ParseIntError;
 use std::string::FromUtf8Error;
 
+#[cfg(feature = "htslib")]
 use rust_htslib::errors::Error as bam_error;
 use thiserror::Error;
 
@@ -62,21 +63,55 @@ pub enum MyError {
     FileContentToUtf8Error{file: String, error: FromUtf8Error},
 
     // 读取bam文件错误
+    #[cfg(feature = "htslib")]
     #[error("Error - read bam file {file}: {error}")]
     ReadBamError{file: String, error: bam_error},
 
     // 读取bam的record错误
+    #[cfg(feature = "htslib")]
     #[error("Error - read bam file {file} record: {error}")]
     ReadBamRecordError{file: String, error: bam_error},
 
+    // noodles读取bam文件错误
+    #[error("Error - read bam file {file}: {error}")]
+    ReadAlignmentError{file: String, error: io::Error},
+
+    // noodles读取bam的record错误
+    #[error("Error - read bam file {file} record: {error}")]
+    ReadAlignmentRecordError{file: String, error: io::Error},
+
     // 读取bed错误
     #[error("Error - Read bed file {file}: {error}")]
     ReadBedError{file: String, error: anyhow::Error},
 
+    // 读取refFlat、genePred错误
+    #[error("Error - read genePred file {file}: {error}")]
+    ReadGenePredError{file: String, error: anyhow::Error},
+
+    // 读取gff3错误
+    #[error("Error - read gff3 file {file}: {error}")]
+    ReadGff3Error{file: String, error: anyhow::Error},
+
+    // 读取注释缓存错误
+    #[error("Error - read annotation cache {file}: {error}")]
+    ReadCacheError{file: String, error: anyhow::Error},
+
+    // 读取--counts-out输出的统计结果错误
+    #[error("Error - read counts file {file}: {error}")]
+    ReadCountsError{file: String, error: anyhow::Error},
+
+    // 编译时没有启用相应feature的压缩格式
+    #[error("Error - {file} is {format} compressed, but this build was compiled without the {format} feature, please decompress it first")]
+    UnsupportedCompressionError{file: String, format: String},
+
     // 读取gtf文件record错误
     #[error("Error - read gtf {file} record: {error}")]
     GtfRecordError{file: String, error: io::Error},
 
+    // bam和注释来自不同基因组版本
+    #[error("Error - {file} and the annotation look like different assemblies: {detail}")]
+    AssemblyMismatchError{file: String, detail: String},
+
     // 参数使用错误
     #[error("Error - {para}")]
     ParaError{para: String},
@@ -0,0 +1,219 @@
+use std::io::BufRead;
+use std::path::{Path, PathBuf};
+
+use anyhow::anyhow;
+
+use crate::{
+    annotation::{AnnotationFormat, AnnotationSource, FeatureStrand, GeneModel, LoadStats, ParseMode},
+    contig::ContigFilter,
+    error::MyError,
+    utils::my_reader,
+};
+
+/// 读取UCSC的refFlat、genePred（包括genePredExt、knownGene以及带bin列的UCSC表格）
+/// 各格式列的含义：
+/// genePred:    name chrom strand txStart txEnd cdsStart cdsEnd exonCount exonStarts exonEnds（genePredExt、knownGene后面还有其他列）
+/// refFlat:     geneName name chrom strand txStart txEnd cdsStart cdsEnd exonCount exonStarts exonEnds
+/// UCSC表格:    bin name chrom strand txStart txEnd cdsStart cdsEnd exonCount exonStarts exonEnds ...
+/// refFlat和带bin列的表格相当于genePred前面多了一列，因此根据strand所在列判断偏移量
+/// 坐标和bed一样是0-based半开区间，每个转录本是一个带exon结构的基因模型
+pub struct GenePredSource {
+    pub path:       PathBuf,
+    pub format:     AnnotationFormat, // RefFlat或GenePred
+    pub parse_mode: ParseMode,
+    pub threads:    usize,            // bgzip压缩时的解压线程数
+}
+
+impl AnnotationSource for GenePredSource {
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+        let mut stats = LoadStats::default();
+        for (i, line) in my_reader(&self.path, self.threads)?.lines().enumerate() {
+            let line = line.map_err(|e| MyError::LinesError{file: self.path.display().to_string(), error: e})?;
+            // 跳过空行和header行，UCSC Table Browser导出的文件第一行以#开头
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue
+            }
+            match parse_genepred_line(&line, self.format) {
+                Ok(model) => if stats.keep_contig(contigs, &model.chr) {
+                    sink(model)
+                },
+                Err(reason) => match self.parse_mode {
+                    ParseMode::Strict => return Err(MyError::ReadGenePredError{file: self.path.display().to_string(), error: anyhow!("line {}: {}", i + 1, reason)}),
+                    ParseMode::Lenient => stats.skipped += 1,
+                },
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// 根据第一行数据的列数和内容判断-r的格式
+/// 第2、3列是数字为bed，第3列是strand为genePred（10列genePred、12列knownGene、15列genePredExt），
+/// 第4列是strand时，11列为refFlat，其他为带bin列的UCSC表格
+pub fn detect_format(file: &Path) -> Result<AnnotationFormat, MyError> {
+    for line in my_reader(file, 1)?.lines() {
+        let line = line.map_err(|e| MyError::LinesError{file: file.display().to_string(), error: e})?;
+        if line.trim().is_empty() || line.starts_with('#') || line.starts_with("track") || line.starts_with("browser") {
+            continue
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() >= 3 && fields[1].parse::<u64>().is_ok() && fields[2].parse::<u64>().is_ok() {
+            return Ok(AnnotationFormat::Bed)
+        }
+        if fields.len() >= 10 && is_strand(fields[2]) {
+            return Ok(AnnotationFormat::GenePred)
+        }
+        if fields.len() == 11 && is_strand(fields[3]) {
+            return Ok(AnnotationFormat::RefFlat)
+        }
+        if fields.len() >= 11 && is_strand(fields[3]) {
+            return Ok(AnnotationFormat::GenePred)
+        }
+        return Err(MyError::ParaError{para: format!("couldn't detect format of {} from its {} columns, please specify --annotation-format", file.display(), fields.len())})
+    }
+    // 空文件当作bed
+    Ok(AnnotationFormat::Bed)
+}
+
+fn is_strand(s: &str) -> bool {
+    matches!(s, "+" | "-" | ".")
+}
+
+/// 解析一行，返回带exon结构的基因模型，出错时返回错误原因
+fn parse_genepred_line(line: &str, format: AnnotationFormat) -> Result<GeneModel, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    // refFlat固定偏移1列，genePred根据strand所在列判断是否有bin列
+    let offset = match format {
+        AnnotationFormat::RefFlat => 1,
+        _ => if fields.len() > 3 && !is_strand(fields[2]) && is_strand(fields[3]) { 1 } else { 0 },
+    };
+    if fields.len() < offset + 10 {
+        return Err(format!("expected at least {} columns, found {}", offset + 10, fields.len()))
+    }
+    let chr = fields[offset + 1];
+    let strand = match fields[offset + 2] {
+        "+" => FeatureStrand::Forward,
+        "-" => FeatureStrand::Reverse,
+        "." => FeatureStrand::Unknown,
+        s => return Err(format!("invalid strand {:?}", s)),
+    };
+    let tx_start: u64 = fields[offset + 3].parse().map_err(|_| format!("invalid txStart {:?}", fields[offset + 3]))?;
+    let tx_end: u64 = fields[offset + 4].parse().map_err(|_| format!("invalid txEnd {:?}", fields[offset + 4]))?;
+    if tx_end <= tx_start {
+        return Err(format!("empty or inverted transcript {}-{}", tx_start, tx_end))
+    }
+    let exon_count: usize = fields[offset + 7].parse().map_err(|_| format!("invalid exonCount {:?}", fields[offset + 7]))?;
+    let exon_starts = parse_coords(fields[offset + 8]).ok_or(format!("invalid exonStarts {:?}", fields[offset + 8]))?;
+    let exon_ends = parse_coords(fields[offset + 9]).ok_or(format!("invalid exonEnds {:?}", fields[offset + 9]))?;
+    if exon_starts.len() != exon_count || exon_ends.len() != exon_count {
+        return Err(format!("exonCount is {}, but found {} exonStarts and {} exonEnds", exon_count, exon_starts.len(), exon_ends.len()))
+    }
+    let mut exons: Vec<(u64, u64)> = Vec::with_capacity(exon_count);
+    for (s, e) in exon_starts.into_iter().zip(exon_ends) {
+        if e <= s {
+            return Err(format!("empty or inverted exon {}-{}", s, e))
+        }
+        exons.push((s, e));
+    }
+    Ok(GeneModel{
+        chr: chr.to_string(),
+        start: tx_start,
+        end: tx_end,
+        strand,
+        name: Some(fields[offset].to_string()),
+        exons: Some(exons),
+    })
+}
+
+/// 解析逗号分隔的坐标，比如"11873,12612,13220,"
+fn parse_coords(s: &str) -> Option<Vec<u64>> {
+    s.split(',').filter(|c| !c.is_empty()).map(|c| c.parse().ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_genepred_{}_{}", std::process::id(), name))
+    }
+
+    /// 写入文件，判断格式后读取所有基因模型
+    fn detect_and_load(name: &str, content: &str) -> (AnnotationFormat, Result<Vec<GeneModel>, MyError>) {
+        let path = temp_file(name);
+        std::fs::write(&path, content).unwrap();
+        let format = detect_format(&path).unwrap();
+        let mut models = Vec::new();
+        let result = GenePredSource{path: path.clone(), format, parse_mode: ParseMode::Strict, threads: 1}.for_each_model(None, &mut |m| models.push(m));
+        std::fs::remove_file(&path).unwrap();
+        (format, result.map(|_| models))
+    }
+
+    /// 同一个转录本（NM_000001，chr1负链，两个exon）在各格式中的表示
+    const GENE: (&str, u64, u64, FeatureStrand) = ("chr1", 1000, 5000, FeatureStrand::Reverse);
+    const EXONS: [(u64, u64); 2] = [(1000, 1500), (4000, 5000)];
+
+    fn assert_model(m: &GeneModel, name: &str) {
+        assert_eq!((m.chr.as_str(), m.start, m.end, m.strand), GENE);
+        assert_eq!(m.name.as_deref(), Some(name));
+        assert_eq!(m.exons.as_deref(), Some(&EXONS[..]));
+    }
+
+    #[test]
+    fn genepred() {
+        let content = "#name\tchrom\tstrand\ttxStart\ttxEnd\tcdsStart\tcdsEnd\texonCount\texonStarts\texonEnds\n\
+                       NM_000001\tchr1\t-\t1000\t5000\t1200\t4500\t2\t1000,4000,\t1500,5000,\n";
+        let (format, models) = detect_and_load("genepred", content);
+        assert_eq!(format, AnnotationFormat::GenePred);
+        assert_model(&models.unwrap()[0], "NM_000001");
+    }
+
+    #[test]
+    fn genepred_ext_and_known_gene() {
+        // genePredExt: 后面有score、name2、cdsStartStat、cdsEndStat、exonFrames
+        let content = "NM_000001\tchr1\t-\t1000\t5000\t1200\t4500\t2\t1000,4000,\t1500,5000,\t0\tGENE1\tcmpl\tcmpl\t1,0,\n";
+        let (format, models) = detect_and_load("genepred_ext", content);
+        assert_eq!(format, AnnotationFormat::GenePred);
+        assert_model(&models.unwrap()[0], "NM_000001");
+        // knownGene: 后面有proteinID、alignID
+        let content = "uc001aaa.3\tchr1\t-\t1000\t5000\t1200\t4500\t2\t1000,4000,\t1500,5000,\tP12345\tuc001aaa.3\n";
+        let (format, models) = detect_and_load("known_gene", content);
+        assert_eq!(format, AnnotationFormat::GenePred);
+        assert_model(&models.unwrap()[0], "uc001aaa.3");
+    }
+
+    #[test]
+    fn refflat() {
+        let content = "GENE1\tNM_000001\tchr1\t-\t1000\t5000\t1200\t4500\t2\t1000,4000,\t1500,5000,\n";
+        let (format, models) = detect_and_load("refflat", content);
+        assert_eq!(format, AnnotationFormat::RefFlat);
+        // name取第2列的转录本名称
+        assert_model(&models.unwrap()[0], "NM_000001");
+    }
+
+    #[test]
+    fn ucsc_table_with_bin() {
+        // ncbiRefSeq等UCSC表格: bin列 + genePredExt
+        let content = "#bin\tname\tchrom\tstrand\ttxStart\ttxEnd\tcdsStart\tcdsEnd\texonCount\texonStarts\texonEnds\tscore\tname2\tcdsStartStat\tcdsEndStat\texonFrames\n\
+                       585\tNM_000001\tchr1\t-\t1000\t5000\t1200\t4500\t2\t1000,4000,\t1500,5000,\t0\tGENE1\tcmpl\tcmpl\t1,0,\n";
+        let (format, models) = detect_and_load("bin", content);
+        assert_eq!(format, AnnotationFormat::GenePred);
+        assert_model(&models.unwrap()[0], "NM_000001");
+    }
+
+    #[test]
+    fn reject_bed() {
+        let bed = "chr1\t1000\t5000\tNM_000001\t0\t-\t1200\t4500\t0\t2\t500,1000,\t0,3000,\n";
+        let (format, _) = detect_and_load("bed", bed);
+        assert_eq!(format, AnnotationFormat::Bed);
+        // 指定为genePred或refFlat时，bed行都是错误行
+        assert_eq!(parse_genepred_line(bed.trim_end(), AnnotationFormat::GenePred).unwrap_err(), "invalid strand \"5000\"");
+        assert_eq!(parse_genepred_line(bed.trim_end(), AnnotationFormat::RefFlat).unwrap_err(), "invalid strand \"NM_000001\"");
+        let path = temp_file("bed_strict");
+        std::fs::write(&path, bed).unwrap();
+        let error = GenePredSource{path: path.clone(), format: AnnotationFormat::GenePred, parse_mode: ParseMode::Strict, threads: 1}.for_each_model(None, &mut |_| ()).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("line 1: invalid strand"), "{}", error);
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::PathBuf;
+
+use anyhow::anyhow;
+
+use crate::{
+    annotation::{AnnotationSource, FeatureStrand, GeneModel, LoadStats, ParseMode},
+    contig::ContigFilter,
+    error::MyError,
+    utils::my_reader,
+};
+
+/// 属于exon这一层的feature类型，-f transcript时排除这些类型
+const EXON_LEVEL: [&str; 6] = ["exon", "CDS", "five_prime_UTR", "three_prime_UTR", "start_codon", "stop_codon"];
+
+/// gff3的一条feature，只保留需要的列
+struct Gff3Feature {
+    chr:          String,
+    start:        u64,
+    end:          u64,
+    strand:       FeatureStrand,
+    feature_type: String,
+    id:           Option<String>, // ID属性
+    parents:      Vec<String>,    // Parent属性，可能有多个，逗号分隔
+}
+
+/// 解码属性值中的%XX，比如"gene%3B1"解码为"gene;1"，不是合法编码的%保持不变
+fn percent_decode(s: &str) -> String {
+    if !s.contains('%') {
+        return s.to_string()
+    }
+    let b = s.as_bytes();
+    let hex = |j: usize| b.get(j).and_then(|&c| (c as char).to_digit(16));
+    let mut out: Vec<u8> = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        match (b[i], hex(i + 1), hex(i + 2)) {
+            (b'%', Some(h), Some(l)) => {
+                out.push((h * 16 + l) as u8);
+                i += 3;
+            },
+            (c, _, _) => {
+                out.push(c);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// gff3 file, get bed6
+/// gff3不同来源的类型名称不统一（比如mRNA、lnc_RNA、transcript），因此根据ID/Parent层级选择feature：
+/// -f gene: 没有Parent且含有子feature（或类型以gene结尾）的feature，排除region、chromosome等
+/// -f transcript: Parent为gene层的feature，排除exon、CDS、UTR等
+/// -f exon: 类型为exon的feature
+/// 其他值: 类型完全一致的feature，比如mRNA、CDS
+/// 遇到##FASTA停止解析，strict遇到错误行直接报错，lenient跳过错误行并计数
+pub struct Gff3Source {
+    pub path:       PathBuf,
+    pub feature:    String,
+    pub parse_mode: ParseMode,
+    pub threads:    usize, // bgzip压缩时的解压线程数
+}
+
+impl AnnotationSource for Gff3Source {
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+        let mut stats = LoadStats::default();
+        // 先读取所有feature，层级关系需要读完才知道，不在bam header中的contig不保存（同一基因的各层feature在同一contig上）
+        let mut features: Vec<Gff3Feature> = Vec::new();
+        for (i, line) in my_reader(&self.path, self.threads)?.lines().enumerate() {
+            let line = line.map_err(|e| MyError::LinesError{file: self.path.display().to_string(), error: e})?;
+            // 后面是序列，不再解析
+            if line.starts_with("##FASTA") {
+                break
+            }
+            // 跳过空行和注释行
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue
+            }
+            match parse_gff3_line(&line) {
+                Ok(f) => if stats.keep_contig(contigs, &f.chr) {
+                    features.push(f)
+                },
+                Err(reason) => match self.parse_mode {
+                    ParseMode::Strict => return Err(MyError::ReadGff3Error{file: self.path.display().to_string(), error: anyhow!("line {}: {}", i + 1, reason)}),
+                    ParseMode::Lenient => stats.skipped += 1,
+                },
+            }
+        }
+        // ID -> index
+        let id2idx: HashMap<&str, usize> = features.iter().enumerate().filter_map(|(i, f)| f.id.as_deref().map(|id| (id, i))).collect();
+        // 是否含有子feature
+        let mut has_children = vec![false; features.len()];
+        for f in &features {
+            for p in &f.parents {
+                if let Some(&i) = id2idx.get(p.as_str()) {
+                    has_children[i] = true;
+                }
+            }
+        }
+        // 计算每个feature的层级，gene为0，transcript为1，exon为2
+        let mut depth: Vec<Option<usize>> = vec![None; features.len()];
+        for i in 0..features.len() {
+            feature_depth(i, &features, &id2idx, &mut depth, 0);
+        }
+        for (i, f) in features.iter().enumerate() {
+            let selected = match self.feature.as_str() {
+                "gene" => depth[i] == Some(0) && (has_children[i] || f.feature_type.ends_with("gene")),
+                "transcript" => depth[i] == Some(1) && !EXON_LEVEL.contains(&f.feature_type.as_str()),
+                "exon" => f.feature_type == "exon",
+                other => f.feature_type == other,
+            };
+            if selected {
+                sink(GeneModel{
+                    chr: f.chr.clone(),
+                    start: f.start,
+                    end: f.end,
+                    strand: f.strand,
+                    name: f.id.clone().or(f.parents.first().cloned()), // exon通常没有ID，用Parent
+                    exons: None,
+                });
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// 解析gff3的一行，出错时返回错误原因
+fn parse_gff3_line(line: &str) -> Result<Gff3Feature, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 9 {
+        return Err(format!("expected 9 columns, found {}", fields.len()))
+    }
+    let start: u64 = fields[3].trim().parse().map_err(|_| format!("invalid start {:?}", fields[3]))?;
+    let end: u64 = fields[4].trim().parse().map_err(|_| format!("invalid end {:?}", fields[4]))?;
+    if end < start {
+        return Err(format!("inverted interval {}-{}", start, end))
+    }
+    let strand = match fields[6].trim() {
+        "+" => FeatureStrand::Forward,
+        "-" => FeatureStrand::Reverse,
+        "." | "?" => FeatureStrand::Unknown,
+        s => return Err(format!("invalid strand {:?}", s)),
+    };
+    let mut id: Option<String> = None;
+    let mut parents: Vec<String> = Vec::new();
+    for attr in fields[8].split(';') {
+        if let Some((k, v)) = attr.trim().split_once('=') {
+            match k {
+                // 先按逗号分隔再解码，值中的逗号编码为%2C
+                "ID" => id = Some(percent_decode(v)),
+                "Parent" => parents.extend(v.split(',').map(percent_decode)),
+                _ => (),
+            }
+        }
+    }
+    Ok(Gff3Feature{
+        chr: fields[0].to_string(),
+        start,
+        end,
+        strand,
+        feature_type: fields[2].to_string(),
+        id,
+        parents,
+    })
+}
+
+/// 递归计算feature的层级，取所有Parent中最小的层级加1
+/// Parent不在文件中时当作gene层缺失，层级为1；通过guard避免Parent循环引用导致无限递归
+fn feature_depth(i: usize, features: &[Gff3Feature], id2idx: &HashMap<&str, usize>, depth: &mut [Option<usize>], guard: usize) -> usize {
+    if let Some(d) = depth[i] {
+        return d
+    }
+    if features[i].parents.is_empty() || guard > 16 {
+        depth[i] = Some(0);
+        return 0
+    }
+    let mut d = usize::MAX;
+    for p in &features[i].parents {
+        let pd = match id2idx.get(p.as_str()) {
+            Some(&j) => feature_depth(j, features, id2idx, depth, guard + 1) + 1,
+            None => 1,
+        };
+        d = d.min(pd);
+    }
+    depth[i] = Some(d);
+    d
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_gff3_{}_{}", std::process::id(), name))
+    }
+
+    /// RefSeq格式的片段：region、cDNA_match这类没有子feature的顶层feature，transcript、lnc_RNA、mRNA等不同的转录本类型，
+    /// 百分号编码的ID（%3B为分号，%2C为逗号），有多个Parent、没有ID的exon，以及##FASTA后面的序列
+    const REFSEQ: &str = "##gff-version 3\n\
+        #!genome-build GRCh38.p14\n\
+        NC_000001.11\tRefSeq\tregion\t1\t248956422\t.\t+\t.\tID=NC_000001.11:1..248956422;Dbxref=taxon:9606;Name=1;gbkey=Src;mol_type=genomic%20DNA\n\
+        NC_000001.11\tBestRefSeq\tgene\t11874\t14409\t.\t+\t.\tID=gene-DDX11L1;Name=DDX11L1;gbkey=Gene;gene_biotype=transcribed_pseudogene\n\
+        NC_000001.11\tBestRefSeq\ttranscript\t11874\t14409\t.\t+\t.\tID=rna-NR_046018.2;Parent=gene-DDX11L1;gbkey=misc_RNA\n\
+        NC_000001.11\tBestRefSeq\texon\t11874\t12227\t.\t+\t.\tID=exon-NR_046018.2-1;Parent=rna-NR_046018.2\n\
+        NC_000001.11\tBestRefSeq\texon\t12613\t12721\t.\t+\t.\tID=exon-NR_046018.2-2;Parent=rna-NR_046018.2\n\
+        NC_000001.11\tRefSeq\tcDNA_match\t11874\t14409\t.\t+\t.\tID=aln0;Target=NR_046018.2 1 1652 +\n\
+        NC_000001.11\tGnomon\tgene\t14696\t24886\t.\t-\t.\tID=gene-LOC124900618;Name=LOC124900618;gene_biotype=lncRNA\n\
+        NC_000001.11\tGnomon\tlnc_RNA\t14696\t24886\t.\t-\t.\tID=rna-XR_007065297.1;Parent=gene-LOC124900618\n\
+        NC_000001.11\tGnomon\texon\t14696\t15038\t.\t-\t.\tID=exon-XR_007065297.1-1;Parent=rna-XR_007065297.1\n\
+        NC_000001.11\tBestRefSeq\tgene\t65419\t71585\t.\t+\t.\tID=gene-OR4F5%3B1;Name=OR4F5;gene_biotype=protein_coding\n\
+        NC_000001.11\tBestRefSeq\tmRNA\t65419\t71585\t.\t+\t.\tID=rna-NM_001005484.2;Parent=gene-OR4F5%3B1\n\
+        NC_000001.11\tGnomon\tmRNA\t65565\t71585\t.\t+\t.\tID=rna-XM_001%2C2;Parent=gene-OR4F5%3B1\n\
+        NC_000001.11\tBestRefSeq\texon\t65565\t65573\t.\t+\t.\tParent=rna-NM_001005484.2,rna-XM_001%2C2\n\
+        NC_000001.11\tBestRefSeq\tCDS\t65565\t65573\t.\t+\t0\tID=cds-NP_001005484.2;Parent=rna-NM_001005484.2\n\
+        ##FASTA\n\
+        >NC_000001.11\n\
+        NNNNNNNNNN\n";
+
+    /// 读取gff3，返回选中feature的名称、位置和链
+    fn load(name: &str, content: &str, feature: &str) -> Vec<(String, u64, u64, FeatureStrand)> {
+        let path = temp_file(name);
+        std::fs::write(&path, content).unwrap();
+        let mut models = Vec::new();
+        let stats = Gff3Source{path: path.clone(), feature: feature.to_string(), parse_mode: ParseMode::Strict, threads: 1}
+            .for_each_model(None, &mut |m| models.push((m.name.unwrap(), m.start, m.end, m.strand))).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(stats.skipped, 0);
+        models
+    }
+
+    fn names(models: &[(String, u64, u64, FeatureStrand)]) -> Vec<&str> {
+        models.iter().map(|m| m.0.as_str()).collect()
+    }
+
+    #[test]
+    fn decode_attributes() {
+        assert_eq!(percent_decode("gene-DDX11L1"), "gene-DDX11L1");
+        assert_eq!(percent_decode("gene%3B1"), "gene;1");
+        assert_eq!(percent_decode("a%2cb%3D%25"), "a,b=%");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        // 不是合法编码的%保持不变
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("%+1"), "%+1");
+    }
+
+    #[test]
+    fn refseq_genes() {
+        // region和cDNA_match没有子feature，不是gene
+        let genes = load("refseq_gene", REFSEQ, "gene");
+        assert_eq!(names(&genes), vec!["gene-DDX11L1", "gene-LOC124900618", "gene-OR4F5;1"]);
+        assert_eq!(genes[1], ("gene-LOC124900618".to_string(), 14696, 24886, FeatureStrand::Reverse));
+    }
+
+    #[test]
+    fn refseq_transcripts() {
+        // transcript、lnc_RNA、mRNA都是gene的下一层，CDS在exon这一层
+        let transcripts = load("refseq_transcript", REFSEQ, "transcript");
+        assert_eq!(names(&transcripts), vec!["rna-NR_046018.2", "rna-XR_007065297.1", "rna-NM_001005484.2", "rna-XM_001,2"]);
+        assert_eq!(names(&load("refseq_mrna", REFSEQ, "mRNA")), vec!["rna-NM_001005484.2", "rna-XM_001,2"]);
+    }
+
+    #[test]
+    fn refseq_exons() {
+        // 有两个Parent的exon只选中一次，没有ID时用第一个Parent作为名称
+        let exons = load("refseq_exon", REFSEQ, "exon");
+        assert_eq!(names(&exons), vec!["exon-NR_046018.2-1", "exon-NR_046018.2-2", "exon-XR_007065297.1-1", "rna-NM_001005484.2"]);
+        assert_eq!(exons[3], ("rna-NM_001005484.2".to_string(), 65565, 65573, FeatureStrand::Forward));
+        // 层级取所有Parent中最小的，两个Parent中的一个不存在时层级为1
+        let content = "chr1\tsrc\tgene\t1\t100\t.\t+\t.\tID=g1\n\
+                       chr1\tsrc\tmRNA\t1\t100\t.\t+\t.\tID=t1;Parent=g1\n\
+                       chr1\tsrc\tncRNA\t1\t50\t.\t+\t.\tID=t2;Parent=missing,t1\n";
+        assert_eq!(names(&load("multi_parent", content, "transcript")), vec!["t1", "t2"]);
+    }
+
+    #[test]
+    fn parent_cycles() {
+        // a和b互为Parent，c的Parent是自己，d是a的子feature，层级计算在guard处停止，这些feature都不是gene或transcript
+        let content = "chr1\tsrc\tgene\t1\t1000\t.\t+\t.\tID=g1\n\
+                       chr1\tsrc\tmRNA\t1\t1000\t.\t+\t.\tID=t1;Parent=g1\n\
+                       chr1\tsrc\tgene\t2000\t3000\t.\t+\t.\tID=a;Parent=b\n\
+                       chr1\tsrc\tgene\t2000\t3000\t.\t+\t.\tID=b;Parent=a\n\
+                       chr1\tsrc\tgene\t4000\t5000\t.\t-\t.\tID=c;Parent=c\n\
+                       chr1\tsrc\tmRNA\t2000\t3000\t.\t+\t.\tID=d;Parent=a\n";
+        assert_eq!(names(&load("cycle_gene", content, "gene")), vec!["g1"]);
+        assert_eq!(names(&load("cycle_transcript", content, "transcript")), vec!["t1"]);
+        // 按类型选择时不受层级影响
+        assert_eq!(names(&load("cycle_mrna", content, "mRNA")), vec!["t1", "d"]);
+    }
+}
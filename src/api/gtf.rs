@@ -1,78 +1,528 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::path::Path;
-
-use bio::{
-    data_structures::interval_tree::IntervalTree,
-    io::{
-        bed::Reader,
-        gff,
-    },
-    utils::Interval,
-};
-use bio_types::strand::Strand;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead};
+use std::path::{Path, PathBuf};
+
+use bio::data_structures::interval_tree::IntervalTree;
 
 use crate::{
+    annotation::{AnnotationSource, FeatureStrand, GeneModel, LoadStats, ParseMode},
+    contig::ContigFilter,
     error::MyError,
     utils::my_reader,
 };
 
-/// 读取指定参考基因bed文件
-/// infer_experiment.py使用bx-python的IntervalTree存储bed位置，这里使用rust-bio的IntervalTree
-/// https://github.com/rust-bio/rust-bio/issues/459
-/// https://docs.rs/bio/latest/bio/data_structures/interval_tree/struct.IntervalTree.html
-/// https://docs.rs/bio/2.2.0/bio/io/bed/index.html
-pub fn load_bed(ref_bed: &Path) -> Result<HashMap<String, IntervalTree<u64, String>>, MyError> {
-    // 读取bed文件
-    //let mut bed_reader = Reader::from_file(ref_bed).map_err(|e| MyError::ReadBedError{file: ref_bed.to_str().unwrap().to_string(), error: e.into()})?;
-    let mut bed_reader = Reader::new(my_reader(ref_bed)?); // 使用my_reader支持读取bed或bed.gz
-    let mut records = bed_reader.records();
-    // 存储bed位置和链信息，相同chr存储在一起
-    let mut gene_ranges: HashMap<String, IntervalTree<u64, String>> = HashMap::new(); // key: chr, value: IntervalTree
-    // 遍历每个record
-    let mut chr: &str;
-    while let Some(Ok(record)) = records.next() {
-        chr = record.chrom();
-        if !gene_ranges.contains_key(chr) {
-            gene_ranges.insert(chr.to_string(), IntervalTree::new());
+/// gtf属性过滤条件，比如`gene_type=protein_coding`、`tag=basic`、`gene_id!=ENSG00000223972,ENSG00000227232`
+/// `key=value`只保留属性值在value中的feature，`key!=value`排除属性值在value中的feature
+/// value可以用逗号分隔多个值，或者用`@file`从文件读取（每行一个值）
+#[derive(Debug, Clone)]
+pub struct AttrFilter {
+    pub key:     String,
+    pub values:  HashSet<String>,
+    pub exclude: bool,
+}
+
+impl AttrFilter {
+    /// 解析`key=value`或`key!=value`
+    pub fn new(filter: &str) -> Result<AttrFilter, MyError> {
+        let (key, value, exclude) = if let Some((k, v)) = filter.split_once("!=") {
+            (k, v, true)
+        } else if let Some((k, v)) = filter.split_once('=') {
+            (k, v, false)
+        } else {
+            return Err(MyError::ParaError{para: format!("--gtf-attr should be key=value or key!=value, not {}", filter)})
+        };
+        if key.is_empty() || value.is_empty() {
+            return Err(MyError::ParaError{para: format!("--gtf-attr should be key=value or key!=value, not {}", filter)})
         }
-        let strand = match record.strand() {
-            Some(s) => match s {
-                Strand::Forward => "+".to_string(),
-                Strand::Reverse => "-".to_string(),
-                Strand::Unknown => "*".to_string(),
+        let values: HashSet<String> = match value.strip_prefix('@') {
+            Some(file) => {
+                let mut values = HashSet::new();
+                for line in my_reader(Path::new(file), 1)?.lines() {
+                    let line = line.map_err(|e| MyError::LinesError{file: file.to_string(), error: e})?;
+                    if !line.trim().is_empty() {
+                        values.insert(line.trim().to_string());
+                    }
+                }
+                values
             },
-            None => "*".to_string(),
+            None => value.split(',').map(|v| v.to_string()).collect(),
         };
-        let tree = gene_ranges.get_mut(chr).unwrap();
-        tree.insert(Interval::new(record.start()..record.end()).unwrap(), strand);
+        Ok(AttrFilter{key: key.to_string(), values, exclude})
+    }
+
+    /// 属性值是否命中，gtf中同一个key可能出现多次，比如多个tag
+    fn hit(&self, attributes: &str) -> bool {
+        attr_values(attributes, &self.key).any(|v| self.values.contains(v))
+    }
+}
+
+/// 写入注释缓存的描述时使用，values排序保证输出稳定
+impl fmt::Display for AttrFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut values: Vec<&str> = self.values.iter().map(|v| v.as_str()).collect();
+        values.sort_unstable();
+        write!(f, "{}{}{}", self.key, if self.exclude { "!=" } else { "=" }, values.join(","))
+    }
+}
+
+/// 是否保留该feature，所有include条件都要满足，任一exclude条件命中则排除
+fn keep_record(attributes: &str, attr_filters: &[AttrFilter]) -> bool {
+    attr_filters.iter().all(|f| f.hit(attributes) != f.exclude)
+}
+
+/// 属性列中key对应的所有值，gtf属性格式为`key "value"; key "value";`，同一个key可能出现多次（比如tag）
+/// 只在需要时查找，不把整个属性列解析为map
+fn attr_values<'a>(attributes: &'a str, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    attributes.split(';').filter_map(move |a| {
+        let (k, v) = a.trim().split_once(' ')?;
+        (k == key).then(|| v.trim().trim_matches('\'').trim_matches('"'))
+    })
+}
+
+/// gtf一行中用到的列，借用读取的行，不分配内存
+struct GtfLine<'a> {
+    seqname:    &'a str,
+    feature:    &'a str,
+    start:      &'a str, // 只有需要的feature才解析为数字
+    end:        &'a str,
+    strand:     FeatureStrand,
+    attributes: &'a str,
+}
+
+impl<'a> GtfLine<'a> {
+    /// 按tab切分出9列，不解析属性列
+    fn parse(line: &'a str) -> Result<Self, String> {
+        let mut fields = [""; 9];
+        let mut n = 0;
+        for (i, f) in line.splitn(9, '\t').enumerate() {
+            fields[i] = f;
+            n = i + 1;
+        }
+        if n < 9 {
+            return Err(format!("expected 9 tab-separated columns, found {}", n))
+        }
+        Ok(GtfLine{
+            seqname: fields[0],
+            feature: fields[2],
+            start: fields[3],
+            end: fields[4],
+            strand: match fields[6] {
+                "+" => FeatureStrand::Forward,
+                "-" => FeatureStrand::Reverse,
+                _ => FeatureStrand::Unknown,
+            },
+            attributes: fields[8],
+        })
+    }
+
+    /// 解析start和end
+    fn interval(&self) -> Result<(u64, u64), String> {
+        let start: u64 = self.start.parse().map_err(|_| format!("invalid start {}", self.start))?;
+        let end: u64 = self.end.parse().map_err(|_| format!("invalid end {}", self.end))?;
+        if end < start {
+            return Err(format!("inverted interval {}-{}", start, end))
+        }
+        Ok((start, end))
+    }
+}
+
+/// gtf file, get bed6
+/// 使用my_reader读取，支持gtf.gz以及bgzip压缩、tabix建索引的gtf（bgzip由多个gzip block组成，MultiGzDecoder会依次解压）
+/// 逐行流式解析，重复使用同一个行缓冲，只切分用到的列，只有指定feature的行才解析坐标和查找属性，不把整个属性列解析为map
+/// 加载GENCODE comprehensive这样的大gtf时内存和时间都少很多；feature名称只在选中的行上查找，gene为gene_id，其他feature优先用transcript_id，merged_exon为gene_id
+/// strict遇到错误行直接报错，lenient跳过错误行并计数
+/// attr_filters不为空时，只保留满足属性过滤条件的feature
+/// feature为merged_exon时，将每个基因所有转录本的exon合并为不重叠的区间，exclude_intronic为true时再去掉位于其他基因内含子中的区域
+pub struct GtfSource {
+    pub path:         PathBuf,
+    pub feature:      String,
+    pub attr_filters: Vec<AttrFilter>,
+    pub parse_mode:   ParseMode,
+    pub exclude_intronic: bool,
+    pub threads:      usize, // bgzip压缩时的解压线程数
+}
+
+/// 合并exon时每个基因的信息
+struct MergedGene {
+    chr:    String,
+    strand: FeatureStrand,
+    name:   String,
+    exons:  Vec<(u64, u64)>,
+}
+
+impl AnnotationSource for GtfSource {
+    fn for_each_model(&self, contigs: Option<&ContigFilter>, sink: &mut dyn FnMut(GeneModel)) -> Result<LoadStats, MyError> {
+        let mut stats = LoadStats::default();
+        let mut reader = my_reader(&self.path, self.threads)?;
+        // merged_exon读取exon，按chr、gene_id收集，读完后再合并
+        let merge = self.feature == "merged_exon";
+        let feature = if merge { "exon" } else { self.feature.as_str() };
+        let mut genes: Vec<MergedGene> = Vec::new();
+        let mut gene_idx: HashMap<String, HashMap<String, usize>> = HashMap::new();
+        // merged_exon时不在bam header中的contig按基因计数，与GeneRanges::features的单位一致
+        let mut unused_genes: HashMap<String, HashSet<String>> = HashMap::new();
+        let mut buf: Vec<u8> = Vec::new();
+        let mut line_num = 0;
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf).map_err(|e| MyError::LinesError{file: self.path.display().to_string(), error: e})? == 0 {
+                break
+            }
+            line_num += 1;
+            // 跳过空行和#开头的注释行
+            let line = match std::str::from_utf8(&buf) {
+                Ok(l) => l.trim_end_matches(['\n', '\r']),
+                Err(_) => match self.parse_mode {
+                    ParseMode::Strict => return Err(self.line_error(line_num, "invalid UTF-8".to_string())),
+                    ParseMode::Lenient => {
+                        stats.skipped += 1;
+                        continue
+                    },
+                },
+            };
+            if line.is_empty() || line.starts_with('#') {
+                continue
+            }
+            let rec = match (GtfLine::parse(line), self.parse_mode) {
+                (Ok(r), _) => r,
+                (Err(e), ParseMode::Strict) => return Err(self.line_error(line_num, e)),
+                (Err(_), ParseMode::Lenient) => {
+                    stats.skipped += 1;
+                    continue
+                },
+            };
+            if rec.feature != feature {
+                continue
+            }
+            let (start, end) = match (rec.interval(), self.parse_mode) {
+                (Ok(i), _) => i,
+                (Err(e), ParseMode::Strict) => return Err(self.line_error(line_num, e)),
+                (Err(_), ParseMode::Lenient) => {
+                    stats.skipped += 1;
+                    continue
+                },
+            };
+            if !self.attr_filters.is_empty() && !keep_record(rec.attributes, &self.attr_filters) {
+                stats.filtered += 1;
+                continue
+            }
+            // 属性过滤之后再检查contig，不在bam header中的feature与加载的feature按同样的条件计数
+            if !merge && !stats.keep_contig(contigs, rec.seqname) {
+                continue
+            }
+            if merge {
+                let gene_id = match (attr_values(rec.attributes, "gene_id").next(), self.parse_mode) {
+                    (Some(g), _) => g,
+                    (None, ParseMode::Strict) => return Err(self.line_error(line_num, "exon without gene_id".to_string())),
+                    (None, ParseMode::Lenient) => {
+                        stats.skipped += 1;
+                        continue
+                    },
+                };
+                if contigs.is_some_and(|c| !c.contains(rec.seqname)) {
+                    match unused_genes.get_mut(rec.seqname) {
+                        Some(g) => {
+                            if !g.contains(gene_id) {
+                                g.insert(gene_id.to_string());
+                            }
+                        },
+                        None => {
+                            unused_genes.insert(rec.seqname.to_string(), HashSet::from([gene_id.to_string()]));
+                        },
+                    }
+                    continue
+                }
+                // 同一个基因的exon只在第一次出现时分配key
+                let idx = match gene_idx.get(rec.seqname).and_then(|g| g.get(gene_id)) {
+                    Some(&idx) => idx,
+                    None => {
+                        genes.push(MergedGene{chr: rec.seqname.to_string(), strand: rec.strand, name: gene_id.to_string(), exons: Vec::new()});
+                        gene_idx.entry(rec.seqname.to_string()).or_default().insert(gene_id.to_string(), genes.len() - 1);
+                        genes.len() - 1
+                    },
+                };
+                genes[idx].exons.push((start, end));
+                continue
+            }
+            // gene用gene_id作为名称，其他feature优先用transcript_id
+            let name = match feature {
+                "gene" => attr_values(rec.attributes, "gene_id").next(),
+                _ => attr_values(rec.attributes, "transcript_id").next().or_else(|| attr_values(rec.attributes, "gene_id").next()),
+            };
+            sink(GeneModel{
+                chr: rec.seqname.to_string(),
+                start,
+                end,
+                strand: rec.strand,
+                name: name.map(|n| n.to_string()),
+                exons: None,
+            });
+        }
+        if merge {
+            for gene in merge_gene_exons(genes, self.exclude_intronic) {
+                sink(gene);
+            }
+            for (chr, genes) in unused_genes {
+                stats.unused_contigs.insert(chr, genes.len());
+            }
+        }
+        Ok(stats)
+    }
+}
+
+impl GtfSource {
+    /// strict模式下错误行的报错
+    fn line_error(&self, line_num: usize, reason: String) -> MyError {
+        MyError::GtfRecordError{file: self.path.display().to_string(), error: io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", line_num, reason))}
     }
-    Ok(gene_ranges)
 }
 
-/// load gtf file, get bed6
-pub fn load_gtf(gtf: &Path, feature: &str) -> Result<HashMap<String, IntervalTree<u64, String>>, MyError> {
-    let mut gene_ranges: HashMap<String, IntervalTree<u64, String>> = HashMap::new(); // key: chr, value: IntervalTree
-    let f = File::open(gtf).map_err(|e| MyError::ReadFileError{file: gtf.to_str().unwrap().to_string(), error: e})?;
-    let mut reader = gff::Reader::new(f, gff::GffType::GTF2);
-    let mut strand: &str;
-    let mut chr: &str;
-    for record in reader.records() {
-        let rec = record.map_err(|e| MyError::GtfRecordError{file: gtf.to_str().unwrap().to_string(), error: e.into()})?;
-        if rec.feature_type() == feature {
-            chr = rec.seqname();
-            if !gene_ranges.contains_key(chr) {
-                gene_ranges.insert(chr.to_string(), IntervalTree::new());
+/// 合并每个基因的exon，得到不重叠的exon区间，基因区间为第一个exon到最后一个exon
+/// exclude_intronic为true时，去掉位于其他基因（不区分链）内含子中的区域，这些区域的read可能来自其他基因的前体RNA，会干扰链特异性判断
+/// 去掉后没有剩余exon的基因不再输出
+fn merge_gene_exons(mut genes: Vec<MergedGene>, exclude_intronic: bool) -> Vec<GeneModel> {
+    for gene in genes.iter_mut() {
+        gene.exons = merge_intervals(std::mem::take(&mut gene.exons));
+    }
+    if exclude_intronic {
+        // 每条chr上所有基因的内含子，value为基因的index
+        let mut introns: HashMap<&str, IntervalTree<u64, usize>> = HashMap::new();
+        for (idx, gene) in genes.iter().enumerate() {
+            let tree = introns.entry(gene.chr.as_str()).or_default();
+            for w in gene.exons.windows(2) {
+                tree.insert(w[0].1..w[1].0, idx);
             }
-            strand = match rec.strand() {
-                Some(Strand::Forward) => "+",
-                Some(Strand::Reverse) => "-",
-                Some(Strand::Unknown) => "",
-                None => "",
+        }
+        let trimmed: Vec<Vec<(u64, u64)>> = genes.iter().enumerate().map(|(idx, gene)| {
+            let (start, end) = (gene.exons[0].0, gene.exons[gene.exons.len() - 1].1);
+            let other: Vec<(u64, u64)> = match introns.get(gene.chr.as_str()) {
+                Some(tree) => tree.find(start..end).filter(|e| *e.data() != idx).map(|e| (e.interval().start, e.interval().end)).collect(),
+                None => Vec::new(),
             };
-            let tree = gene_ranges.get_mut(chr).unwrap();
-            tree.insert(Interval::new((*rec.start())..(*rec.end())).unwrap(), strand.to_string());
+            subtract_intervals(&gene.exons, merge_intervals(other))
+        }).collect();
+        for (gene, exons) in genes.iter_mut().zip(trimmed) {
+            gene.exons = exons;
+        }
+    }
+    genes.into_iter().filter(|g| !g.exons.is_empty()).map(|g| GeneModel{
+        chr: g.chr,
+        start: g.exons[0].0,
+        end: g.exons[g.exons.len() - 1].1,
+        strand: g.strand,
+        name: Some(g.name),
+        exons: Some(g.exons),
+    }).collect()
+}
+
+/// 排序并合并重叠或相邻的区间
+fn merge_intervals(mut intervals: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    intervals.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for (s, e) in intervals {
+        match merged.last_mut() {
+            Some(last) if s <= last.1 => last.1 = last.1.max(e),
+            _ => merged.push((s, e)),
+        }
+    }
+    merged
+}
+
+/// 从排序后不重叠的区间中去掉另一组排序后不重叠的区间
+fn subtract_intervals(intervals: &[(u64, u64)], remove: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    let mut out: Vec<(u64, u64)> = Vec::with_capacity(intervals.len());
+    for &(s, e) in intervals {
+        let mut cur = s;
+        for &(rs, re) in remove.iter().filter(|(rs, re)| *rs < e && *re > s) {
+            if rs > cur {
+                out.push((cur, rs));
+            }
+            cur = cur.max(re);
+        }
+        if cur < e {
+            out.push((cur, e));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::{GeneRanges, UnstrandedMode};
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_gtf_{}_{}", std::process::id(), name))
+    }
+
+    const GTF: &str = "#!genome-build GRCh38\n\
+chr1\tsrc\tgene\t100\t900\t.\t+\t.\tgene_id \"g1\"; gene_type \"protein_coding\";\n\
+chr1\tsrc\ttranscript\t100\t900\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\";\n\
+chr1\tsrc\texon\t100\t200\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\"; exon_number 1;\n\
+chr1\tsrc\texon\t800\t900\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\"; exon_number 2;\n\
+chr2\tsrc\tgene\t50\t500\t.\t-\t.\tgene_id \"g2\"; gene_type \"lncRNA\";\n\
+chr2\tsrc\texon\t50\t500\t.\t-\t.\tgene_id \"g2\";\n";
+
+    fn names(feature: &str) -> Vec<Option<String>> {
+        let path = temp_file(&format!("{}.gtf", feature));
+        std::fs::write(&path, GTF).unwrap();
+        let source = GtfSource{path: path.clone(), feature: feature.to_string(), attr_filters: Vec::new(), parse_mode: ParseMode::Strict, exclude_intronic: false, threads: 1};
+        let models = source.models().unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let mut names: Vec<Option<String>> = models.into_iter().map(|m| m.name).collect();
+        names.sort_unstable();
+        names
+    }
+
+    #[test]
+    fn feature_names() {
+        let name = |n: &str| Some(n.to_string());
+        assert_eq!(names("gene"), vec![name("g1"), name("g2")]);
+        assert_eq!(names("transcript"), vec![name("t1")]);
+        // 没有transcript_id时用gene_id
+        assert_eq!(names("exon"), vec![name("g2"), name("t1"), name("t1")]);
+        assert_eq!(names("merged_exon"), vec![name("g1"), name("g2")]);
+    }
+
+    #[test]
+    fn attr_values_finds_repeated_keys() {
+        let attributes = "gene_id \"g1\"; tag \"basic\"; tag \"CCDS\"; level 2;";
+        assert_eq!(attr_values(attributes, "tag").collect::<Vec<_>>(), vec!["basic", "CCDS"]);
+        assert_eq!(attr_values(attributes, "level").collect::<Vec<_>>(), vec!["2"]);
+        assert_eq!(attr_values(attributes, "gene_type").next(), None);
+    }
+
+    #[test]
+    fn merge_and_subtract_intervals() {
+        // 重叠和相邻（gtf为闭区间，end等于下一个start）的区间合并
+        assert_eq!(merge_intervals(vec![(500, 600), (100, 200), (150, 250), (250, 300), (550, 580)]), vec![(100, 300), (500, 600)]);
+        assert_eq!(merge_intervals(Vec::new()), Vec::new());
+        let exons = [(100, 200), (500, 600), (900, 1000)];
+        assert_eq!(subtract_intervals(&exons, Vec::new()), exons.to_vec());
+        assert_eq!(subtract_intervals(&exons, vec![(150, 550), (950, 1000)]), vec![(100, 150), (550, 600), (900, 950)]);
+        assert_eq!(subtract_intervals(&exons, vec![(0, 2000)]), Vec::new());
+    }
+
+    /// A的两个转录本的exon互相重叠；chr1上N（负链）位于H（正链）的内含子中，chr2上N2（正链）位于H2（负链）的内含子中，
+    /// P的exon一部分位于H2的内含子中
+    const NESTED_GTF: &str = "\
+chr1\tsrc\texon\t100\t200\t.\t+\t.\tgene_id \"A\"; transcript_id \"A.1\";\n\
+chr1\tsrc\texon\t500\t600\t.\t+\t.\tgene_id \"A\"; transcript_id \"A.1\";\n\
+chr1\tsrc\texon\t150\t250\t.\t+\t.\tgene_id \"A\"; transcript_id \"A.2\";\n\
+chr1\tsrc\texon\t550\t700\t.\t+\t.\tgene_id \"A\"; transcript_id \"A.2\";\n\
+chr1\tsrc\texon\t900\t1000\t.\t+\t.\tgene_id \"A\"; transcript_id \"A.2\";\n\
+chr1\tsrc\texon\t10000\t10100\t.\t+\t.\tgene_id \"H\"; transcript_id \"H.1\";\n\
+chr1\tsrc\texon\t15000\t15100\t.\t+\t.\tgene_id \"H\"; transcript_id \"H.1\";\n\
+chr1\tsrc\texon\t12000\t12100\t.\t-\t.\tgene_id \"N\"; transcript_id \"N.1\";\n\
+chr1\tsrc\texon\t13000\t13100\t.\t-\t.\tgene_id \"N\"; transcript_id \"N.1\";\n\
+chr2\tsrc\texon\t10000\t10100\t.\t-\t.\tgene_id \"H2\"; transcript_id \"H2.1\";\n\
+chr2\tsrc\texon\t15000\t15100\t.\t-\t.\tgene_id \"H2\"; transcript_id \"H2.1\";\n\
+chr2\tsrc\texon\t12000\t12100\t.\t+\t.\tgene_id \"N2\"; transcript_id \"N2.1\";\n\
+chr2\tsrc\texon\t14900\t15300\t.\t+\t.\tgene_id \"P\"; transcript_id \"P.1\";\n";
+
+    /// 合并后每个基因的名称、链和exon
+    type Merged = (String, FeatureStrand, Vec<(u64, u64)>);
+
+    /// 读取NESTED_GTF的merged_exon，返回合并后的基因，以及GeneRanges中加载、跳过和过滤的feature数量
+    fn merged(name: &str, exclude_intronic: bool) -> (Vec<Merged>, (usize, usize, usize)) {
+        let path = temp_file(name);
+        std::fs::write(&path, NESTED_GTF).unwrap();
+        let source = GtfSource{path: path.clone(), feature: "merged_exon".to_string(), attr_filters: Vec::new(), parse_mode: ParseMode::Strict, exclude_intronic, threads: 1};
+        let genes = source.models().unwrap().into_iter().map(|m| (m.name.unwrap(), m.strand, m.exons.unwrap())).collect();
+        let ranges = GeneRanges::from_source(&source, UnstrandedMode::Separate, true, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (genes, (ranges.features, ranges.skipped, ranges.filtered))
+    }
+
+    #[test]
+    fn merge_overlapping_transcripts() {
+        let (genes, stats) = merged("merge", false);
+        // 同一基因两个转录本的exon合并为一组不重叠的exon
+        assert_eq!(genes[0], ("A".to_string(), FeatureStrand::Forward, vec![(100, 250), (500, 700), (900, 1000)]));
+        // 不去掉内含子区域时，嵌套的基因都保留
+        assert_eq!(genes.iter().map(|g| g.0.as_str()).collect::<Vec<_>>(), vec!["A", "H", "N", "H2", "N2", "P"]);
+        assert_eq!(genes[2].2, vec![(12000, 12100), (13000, 13100)]);
+        assert_eq!(stats, (6, 0, 0));
+    }
+
+    #[test]
+    fn exclude_intronic_regions() {
+        let (genes, stats) = merged("exclude_intronic", true);
+        // 两条链上位于其他基因内含子中的N、N2都被整个去掉，不计入skipped和filtered，只是不再加载
+        assert_eq!(genes, vec![
+            ("A".to_string(), FeatureStrand::Forward, vec![(100, 250), (500, 700), (900, 1000)]),
+            ("H".to_string(), FeatureStrand::Forward, vec![(10000, 10100), (15000, 15100)]),
+            ("H2".to_string(), FeatureStrand::Reverse, vec![(10000, 10100), (15000, 15100)]),
+            ("P".to_string(), FeatureStrand::Forward, vec![(15000, 15300)]),
+        ]);
+        assert_eq!(stats, (4, 0, 0));
+    }
+
+    /// t5没有gene_type，t3没有tag，t1和t4有多个tag
+    const ATTR_GTF: &str = "\
+chr1\tsrc\ttranscript\t100\t200\t.\t+\t.\tgene_id \"g1\"; transcript_id \"t1\"; gene_type \"protein_coding\"; tag \"basic\"; tag \"CCDS\";\n\
+chr1\tsrc\ttranscript\t300\t400\t.\t-\t.\tgene_id \"g2\"; transcript_id \"t2\"; gene_type \"lncRNA\"; tag \"basic\";\n\
+chr1\tsrc\ttranscript\t500\t600\t.\t+\t.\tgene_id \"g3\"; transcript_id \"t3\"; gene_type \"protein_coding\";\n\
+chr1\tsrc\ttranscript\t700\t800\t.\t-\t.\tgene_id \"g4\"; transcript_id \"t4\"; gene_type \"misc_RNA\"; tag \"mRNA_start_NF\"; tag \"cds_start_NF\";\n\
+chr1\tsrc\ttranscript\t900\t1000\t.\t+\t.\tgene_id \"g5\"; transcript_id \"t5\"; tag \"basic\";\n";
+
+    /// 按过滤条件读取ATTR_GTF，返回保留的transcript和被过滤的数量
+    fn filtered(name: &str, filters: &[&str]) -> (Vec<String>, usize) {
+        let path = temp_file(name);
+        std::fs::write(&path, ATTR_GTF).unwrap();
+        let attr_filters = filters.iter().map(|f| AttrFilter::new(f).unwrap()).collect();
+        let source = GtfSource{path: path.clone(), feature: "transcript".to_string(), attr_filters, parse_mode: ParseMode::Strict, exclude_intronic: false, threads: 1};
+        let mut kept = Vec::new();
+        let stats = source.for_each_model(None, &mut |m| kept.push(m.name.unwrap())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        (kept, stats.filtered)
+    }
+
+    fn kept(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        // 没有该属性的feature不满足key=value，也不会被key!=value排除
+        assert_eq!(filtered("include", &["gene_type=protein_coding"]), (kept(&["t1", "t3"]), 3));
+        assert_eq!(filtered("exclude", &["gene_type!=protein_coding"]), (kept(&["t2", "t4", "t5"]), 2));
+        assert_eq!(filtered("exclude_missing", &["tag!=basic"]), (kept(&["t3", "t4"]), 3));
+        // 多个条件都要满足
+        assert_eq!(filtered("both", &["gene_type=protein_coding", "tag=basic"]), (kept(&["t1"]), 4));
+        assert_eq!(filtered("include_exclude", &["tag=basic", "gene_type!=lncRNA"]), (kept(&["t1", "t5"]), 3));
+    }
+
+    #[test]
+    fn value_lists() {
+        assert_eq!(filtered("list", &["gene_type=protein_coding,lncRNA"]), (kept(&["t1", "t2", "t3"]), 2));
+        assert_eq!(filtered("exclude_list", &["gene_type!=protein_coding,lncRNA"]), (kept(&["t4", "t5"]), 3));
+        // @file每行一个值，忽略空行和首尾空白
+        let list = temp_file("values.txt");
+        std::fs::write(&list, "lncRNA\n  misc_RNA \n\n").unwrap();
+        let include = filtered("file", &[&format!("gene_type=@{}", list.display())]);
+        let exclude = filtered("exclude_file", &[&format!("gene_type!=@{}", list.display())]);
+        std::fs::remove_file(&list).unwrap();
+        assert_eq!(include, (kept(&["t2", "t4"]), 3));
+        assert_eq!(exclude, (kept(&["t1", "t3", "t5"]), 2));
+    }
+
+    #[test]
+    fn repeated_keys() {
+        // 任一tag命中即可
+        assert_eq!(filtered("tag_second", &["tag=CCDS"]), (kept(&["t1"]), 4));
+        assert_eq!(filtered("tag_list", &["tag=CCDS,cds_start_NF"]), (kept(&["t1", "t4"]), 3));
+        assert_eq!(filtered("tag_exclude", &["tag!=cds_start_NF"]), (kept(&["t1", "t2", "t3", "t5"]), 1));
+    }
+
+    #[test]
+    fn parse_filters() {
+        let f = AttrFilter::new("gene_id!=ENSG2,ENSG1").unwrap();
+        assert_eq!((f.key.as_str(), f.exclude, f.to_string()), ("gene_id", true, "gene_id!=ENSG1,ENSG2".to_string()));
+        for bad in ["gene_type", "=lncRNA", "gene_type=", "gene_type!="] {
+            assert!(AttrFilter::new(bad).is_err(), "{}", bad);
         }
     }
-    Ok(gene_ranges)
 }
@@ -1,258 +1,918 @@
-use std::collections::{HashMap, HashSet};
-use std::path::{Path, PathBuf};
-
-use rust_htslib::bam::{Read, Reader, Record, Header, HeaderView};
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::{
-    bed::load_bed,
-    gtf::load_gtf,
+    alignment::{find_index, open_indexed, open_reader, AlignmentReader, AlignmentRecord, IndexedAlignmentReader},
+    annotation::{GeneRanges, UnstrandedMode},
+    contig::{assembly_warnings, preview, read_alias_table, ContigFilter},
+    counts::{CountAccumulator, ReadStats, StrandCounts, ONLY_FORWARD, ONLY_REVERSE, ONLY_UNKNOWN},
     error::MyError,
+    overlap::IntervalIndex,
+    parse_paras::ParsedParas,
+    stats::{bootstrap_intervals, wilson_interval, Bootstrap, BootstrapUnit, Z_95},
 };
 
-/// 开始分析
-pub fn run_infer(bam_file: &Path, ref_bed: Option<PathBuf>, gtf: Option<PathBuf>, feature: &str, sample_size: usize, q_cut: u8) -> Result<(), MyError> {
-    // 读取bam文件
-    //let mut bam_reader = rust_htslib::bam::IndexedReader::from_path(bam_file).map_err(|e| MyError::ReadBamError{file: bam_file.to_str().unwrap().to_string(), error: e})?; // 这样读取会导致下面循环读取record时返回None退出循环
-    let mut bam_reader = Reader::from_path(bam_file).map_err(|e| MyError::ReadBamError{file: bam_file.to_str().unwrap().to_string(), error: e})?;
-    // 需要根据tid从header中获取chr，参考：https://github.com/rust-bio/rust-htslib/issues/288
-    let header = Header::from_template(&bam_reader.header());
-    let head_view = HeaderView::from_header(&header);
-    // 读取指定参考基因bed文件
-    let gene_ranges = match (ref_bed, gtf) { // HashMap<String, IntervalTree>
-        (Some(r), None) => load_bed(&r)?,
-        (None, Some(g)) => load_gtf(&g, feature)?,
-        _ => unreachable!(),
-    };
-    // 记录record数量，达到指定的sample_size数量则停止
-    let mut count: usize = 0;
-    let mut p_strandness: HashMap<String, f64> = HashMap::new(); // key: read_id(1/2) + map_strand(+/-) + strand_from_gene(“:”拼接的基因strand), value: count
-    let mut s_strandness: HashMap<String, f64> = HashMap::new(); // key: map_strand(+/-) + strand_from_gene(“:”拼接的基因strand), value: count
-    let mut read_id_map_strand_gene_strand: String;
-    let mut p_strandness_sum: f64 = 0.0;
-    let mut s_strandness_sum: f64 = 0.0;
-    let mut read_start: u64;
-    let mut read_end: u64;
-    // 先声明一个record，后面使用bam的read方法往里面写入新record，避免每次重新声明record，更高效
-    let mut record = Record::new();
-    // 遍历bam每条read
-    while let Some(result) = bam_reader.read(&mut record) {
-        // 达到指定的sample_size数量则停止
-        if count >= sample_size {
-            break
+/// 同时含有双端和单端read时，用哪部分给出结论
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Majority, // read数量多的那部分
+    Paired,   // 只看双端read
+    Single,   // 只看单端read
+}
+
+impl FromStr for Layout {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "majority" => Ok(Layout::Majority),
+            "paired" => Ok(Layout::Paired),
+            "single" => Ok(Layout::Single),
+            _ => Err(MyError::ParaError{para: format!("-l only support majority, paired or single, not {}", s)}),
         }
-        // 这里result是`Result<()>`解析下没报错就行
-        result.map_err(|e| MyError::ReadBamRecordError{file: bam_file.to_str().unwrap().to_string(), error: e})?;
-        // skip low quanlity
-        if record.is_quality_check_failed() {
-            continue
+    }
+}
+
+impl Layout {
+    /// 是否用双端read给出结论，只有一种read时直接使用那一种
+    pub fn use_paired(&self, paired: u64, single: u64) -> bool {
+        if single == 0 || paired == 0 {
+            return single == 0
         }
-        // skip duplicate read
-        if record.is_duplicate() {
-            continue
+        match self {
+            Layout::Majority => paired >= single,
+            Layout::Paired => true,
+            Layout::Single => false,
         }
-        // skip non primary hit
-        if record.is_secondary() {
-            continue
+    }
+}
+
+impl fmt::Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Layout::Majority => write!(f, "majority"),
+            Layout::Paired => write!(f, "paired"),
+            Layout::Single => write!(f, "single"),
         }
-        // skip unmap read
-        if record.is_unmapped() {
-            continue
+    }
+}
+
+/// 开始分析
+pub fn run_infer(paras: &ParsedParas) -> Result<(), MyError> {
+    let bam_file = &paras.input_file;
+    // 读取bam文件，默认使用htslib，只启用noodles feature（没有htslib）时使用noodles
+    // htslib的线程池解压bam/cram
+    let mut bam_reader = open_reader(bam_file, paras.threads)?;
+    // 读取指定参考基因注释，-f exon时使用基因模型的exon结构，只加载bam header中有的contig
+    // 注释和bam的contig命名不一致时自动转换，--chr-alias指定的别名表优先
+    let bam_contigs: Vec<String> = bam_reader.header().names.clone();
+    let bam_lengths: Vec<u64> = bam_reader.header().lengths.clone();
+    let mut contigs = ContigFilter::new(&bam_contigs, &bam_lengths);
+    if let Some(alias) = &paras.chr_alias {
+        contigs.add_aliases(read_alias_table(alias)?);
+    }
+    // bgzip压缩的注释也用-t指定的线程数解压
+    let source = paras.annotation.annotation_source(paras.threads)?;
+    let gene_ranges = GeneRanges::from_source(source.as_ref(), paras.unstranded, paras.annotation.use_exons(), Some(&contigs))?;
+    gene_ranges.report();
+    // 没有一个contig能对应上时，后面统计不到任何read，给出两边的contig方便排查
+    if gene_ranges.ranges.is_empty() && !gene_ranges.unused_contigs.is_empty() {
+        println!("Warning - none of the annotation contigs match the BAM header, please check the contig names or use --chr-alias");
+        println!("BAM contigs: {}", preview(bam_contigs.clone()));
+        println!("Annotation contigs: {}", preview(gene_ranges.unused_contigs.keys().cloned().collect()));
+    }
+    // 根据@SQ的长度和contig检查bam和注释是否来自同一基因组版本
+    let lengths: HashMap<String, u64> = bam_contigs.iter().cloned().zip(bam_lengths.iter().copied()).collect();
+    for warning in assembly_warnings(&gene_ranges, &lengths, paras.assembly_check, bam_file)? {
+        println!("{}", warning);
+    }
+    // 按bam的tid存储IntervalIndex，统计时直接用record的tid取，不需要每条read都转换chr名称
+    let mut gene_ranges = gene_ranges.ranges;
+    // 按基因bootstrap时计算每个区间所属的locus，统计时再按基因记录
+    let track_genes = paras.bootstrap.is_some_and(|b| b.unit == BootstrapUnit::Genes);
+    let trees: Vec<Option<IntervalIndex>> = bam_contigs.iter().map(|chr| gene_ranges.remove(chr).map(|intervals| {
+        let index = IntervalIndex::new(intervals);
+        if track_genes { index.with_loci() } else { index }
+    })).collect();
+    // --whole-file且多线程时，有索引的bam按contig并行统计，统计的read与单线程一致
+    // 只有找不到索引文件时才退回单线程，索引损坏、打不开等错误直接报错
+    let stats = if paras.whole_file && paras.threads > 1 {
+        if find_index(bam_file).is_some() {
+            count_parallel(&|| open_indexed(bam_file), &trees, &bam_lengths, paras)?
+        } else {
+            println!("Warning - {} has no index, scanning it with a single reader", bam_file.display());
+            count_sequential(bam_reader.as_mut(), &trees, paras)?
         }
-        // 舍弃质量分数低于自定义阈值的record
-        if record.mapq() < q_cut {
-            continue
+    } else {
+        count_sequential(bam_reader.as_mut(), &trees, paras)?
+    };
+    // 可以写入文件，之后用merge子命令和其他区域的统计合并
+    let acc = CountAccumulator{contigs: bam_contigs, stats, unstranded: paras.unstranded, unknown_mate_as_single: paras.unknown_mate_as_single};
+    if let Some(output) = &paras.counts_out {
+        acc.write(output)?;
+    }
+    report_counts(&acc, paras.layout, paras.per_contig, paras.bootstrap);
+    Ok(())
+}
+
+/// 最后统计，并打印结果，单次统计和merge子命令共用
+/// 每个比例后面输出Wilson 95%置信区间，指定bootstrap时再输出bootstrap 95%置信区间
+pub fn report_counts(acc: &CountAccumulator, layout: Layout, per_contig: bool, bootstrap: Option<Bootstrap>) {
+    let counts = acc.stats.total();
+    let count = acc.stats.count;
+    let unknown_mate = acc.stats.unknown_mate;
+    if unknown_mate > 0 {
+        if acc.unknown_mate_as_single {
+            println!("{} paired reads were neither first nor last in template, counted as SingleEnd reads", unknown_mate);
+        } else {
+            println!("{} paired reads were neither first nor last in template, skipped", unknown_mate);
         }
-        // 根据tid从header中获取当前record的chr
-        let chrom = String::from_utf8(head_view.tid2name(record.tid() as u32).to_vec()).unwrap();
-        // 统计
-        //println!("{}", count);
-        if record.is_paired() {
-            //read_id_map_strand_gene_strand = if is_in_flag(record.flags(), SamFlag::FIRST_IN_PAIR) { // First in pair, 64
-            read_id_map_strand_gene_strand = if record.is_first_in_template() { // First in pair, 64
-                "1".to_string()
-            //} else if is_in_flag(record.flags(), SamFlag::SECOND_IN_PAIR) { // Second in pair, 128
-            } else if record.is_last_in_template() { // Second in pair, 128
-                "2".to_string()
-            } else {
-                "0".to_string() // 不应该运行到这里
-            };
-            if record.is_reverse() {
-                read_id_map_strand_gene_strand += "-";
+    }
+    if acc.stats.budget_hit {
+        println!("Partial results: scan budget (--max-records/--max-seconds) was hit after {} records", acc.stats.records);
+    }
+    println!("Total {} usable reads were sampled", count);
+    let show_unstranded = acc.unstranded == UnstrandedMode::Separate;
+    // bootstrap的重抽样单位，按基因抽样时为每个基因的统计，否则为每个contig的统计（按read抽样时只用合计）
+    let genes: Vec<StrandCounts>;
+    let units: &[StrandCounts] = match bootstrap {
+        Some(b) if b.unit == BootstrapUnit::Genes => {
+            genes = acc.stats.genes.values().cloned().collect();
+            &genes
+        },
+        _ => &acc.stats.contigs,
+    };
+    let p_strandness_sum = counts.paired_sum();
+    let s_strandness_sum = counts.single_sum();
+    match (p_strandness_sum == 0, s_strandness_sum == 0) {
+        (false, true) => {
+            println!("This is PairEnd Data");
+            report_paired(&counts, units, show_unstranded, bootstrap);
+        },
+        (true, false) => {
+            println!("This is SingleEnd Data");
+            report_single(&counts, units, show_unstranded, bootstrap);
+        },
+        (false, false) => {
+            // 同时含有双端和单端read，比如合并了多个lane或含有rescued singletons，两部分都输出，结论只根据-l选定的那部分
+            let use_paired = layout.use_paired(p_strandness_sum, s_strandness_sum);
+            println!("This is Mixed Data: {} PairEnd reads, {} SingleEnd reads", p_strandness_sum, s_strandness_sum);
+            println!("Layout used for the verdict ({}): {}", layout, if use_paired { "PairEnd" } else { "SingleEnd" });
+            if use_paired {
+                println!("PairEnd reads:");
+                report_paired(&counts, units, show_unstranded, bootstrap);
+                println!("SingleEnd reads:");
+                report_single(&counts, units, show_unstranded, bootstrap);
             } else {
-                read_id_map_strand_gene_strand += "+";
+                println!("SingleEnd reads:");
+                report_single(&counts, units, show_unstranded, bootstrap);
+                println!("PairEnd reads:");
+                report_paired(&counts, units, show_unstranded, bootstrap);
             }
-            read_start = record.pos() as u64;
-            read_end = read_start + record.seq_len() as u64; // 这里加上原始read长度，比如150
-            if let Some(tree) = gene_ranges.get(&chrom) {
-                let tmp = tree.find(read_start..read_end);
-                if tmp.clone().count() == 0 {
-                    continue
-                }
-                let tmp_str = tmp.map(|s| s.data().clone()).collect::<HashSet<_>>().into_iter().collect::<Vec<String>>().join(":");
-                read_id_map_strand_gene_strand += &tmp_str;
-                match p_strandness.get_mut(&read_id_map_strand_gene_strand) {
-                    Some(s) => *s += 1.0,
-                    None => {
-                        p_strandness.insert(read_id_map_strand_gene_strand, 1.0);
-                    },
+        },
+        (true, true) => println!("Unknown Data type"),
+    }
+    if p_strandness_sum + s_strandness_sum > 0 {
+        report_verdict(&counts, layout.use_paired(p_strandness_sum, s_strandness_sum));
+    }
+    // 每个contig的统计
+    if per_contig {
+        report_contigs(&acc.contigs, &acc.stats);
+    }
+}
+
+/// --ci-width时每统计多少条usable read检查一次是否收敛
+const CHECK_INTERVAL: usize = 1000;
+
+/// 每读取多少条record检查一次时间，多线程时各线程也按这个数量汇总读取的record
+const BUDGET_BATCH: usize = 1024;
+
+/// 扫描预算，读取的record数量（包括被过滤的）或扫描时间超出时停止，比如注释和bam的contig对不上、大部分read在基因间区时不会读完整个文件
+struct ScanBudget {
+    max_records: usize,
+    deadline:    Option<Instant>,
+}
+
+impl ScanBudget {
+    /// 从开始扫描时计时
+    fn new(paras: &ParsedParas) -> Self {
+        ScanBudget{
+            max_records: paras.max_records.unwrap_or(usize::MAX),
+            deadline: paras.max_seconds.map(|s| Instant::now() + Duration::from_secs_f64(s)),
+        }
+    }
+
+    /// 已读取records条record时是否超出预算，每BUDGET_BATCH条检查一次时间
+    fn exceeded(&self, records: usize) -> bool {
+        records >= self.max_records || (records.is_multiple_of(BUDGET_BATCH) && self.deadline.is_some_and(|d| Instant::now() >= d))
+    }
+}
+
+/// 依次读取bam的每条record进行统计，不是--whole-file时达到sample_size数量则停止
+/// 指定了--ci-width时，至少统计--min-reads条usable read后，每CHECK_INTERVAL条检查一次占多数的链特异性比例的置信区间，足够窄时提前停止
+fn count_sequential(bam_reader: &mut dyn AlignmentReader, trees: &[Option<IntervalIndex>], paras: &ParsedParas) -> Result<ReadStats, MyError> {
+    let limit = if paras.whole_file { usize::MAX } else { paras.sample_size };
+    let mut stats = ReadStats::new(trees.len());
+    let mut next_check = paras.min_reads.max(CHECK_INTERVAL);
+    let mut converged = false;
+    let budget = ScanBudget::new(paras);
+    // 遍历bam每条read
+    bam_reader.for_each_record(&mut |record| {
+        // 达到指定的sample_size数量则停止
+        if stats.count >= limit {
+            return false
+        }
+        // 超出--max-records或--max-seconds则停止，结果不完整
+        if budget.exceeded(stats.records) {
+            stats.budget_hit = true;
+            return false
+        }
+        stats.records += 1;
+        count_record(record, trees, paras, &mut stats);
+        if let Some(width) = paras.ci_width {
+            if stats.count >= next_check {
+                next_check = stats.count + CHECK_INTERVAL;
+                let (lower, upper) = dominant_interval(&stats, paras.layout);
+                if upper - lower < width {
+                    converged = true;
+                    return false
                 }
-                p_strandness_sum += 1.0;
-                count += 1;
             }
+        }
+        true
+    })?;
+    if let Some(width) = paras.ci_width {
+        let (lower, upper) = dominant_interval(&stats, paras.layout);
+        if converged {
+            println!("Stopped early after {} usable reads: 95% CI of the dominant fraction ({:.4}, {:.4}) is narrower than {}", stats.count, lower, upper, width);
         } else {
-            read_id_map_strand_gene_strand = if record.is_reverse() {
-                "-".to_string()
-            } else {
-                "+".to_string()
-            };
-            read_start = record.pos() as u64;
-            read_end = read_start + record.seq_len() as u64; // 这里加上原始read长度，比如150
-            if let Some(tree) = gene_ranges.get(&chrom) {
-                let tmp = tree.find(read_start..read_end);
-                if tmp.clone().count() == 0 {
-                    continue
+            println!("Warning - not converged after {} usable reads: 95% CI of the dominant fraction ({:.4}, {:.4}) is not narrower than {}", stats.count, lower, upper, width);
+        }
+    }
+    Ok(stats)
+}
+
+/// -l选定的那部分read中，两种链特异性中占多数的那种所占比例的95% Wilson置信区间，与结论使用相同的read
+fn dominant_interval(stats: &ReadStats, layout: Layout) -> (f64, f64) {
+    let total = stats.total();
+    let classes = if layout.use_paired(total.paired_sum(), total.single_sum()) { paired_classes(&total) } else { single_classes(&total) };
+    wilson_interval(classes[0].max(classes[1]), classes.iter().sum(), Z_95)
+}
+
+/// 有索引的bam按contig分给多个线程统计，每个线程用open打开自己的IndexedAlignmentReader，最后合并各线程的统计
+/// 每条record只属于一个tid，没有比对位置的record（tid为-1）最后单独读取，所以统计的read和records与单线程扫描整个文件一致
+/// 各线程每读取BUDGET_BATCH条record汇总一次并检查扫描预算，所以--max-records是近似的，最多多读取线程数 * BUDGET_BATCH条
+fn count_parallel(open: &(dyn Fn() -> Result<Box<dyn IndexedAlignmentReader>, MyError> + Sync), trees: &[Option<IntervalIndex>], lengths: &[u64], paras: &ParsedParas) -> Result<ReadStats, MyError> {
+    // 长的contig先处理，线程间负载更均衡，None为没有比对位置的record
+    let mut tids: Vec<usize> = (0..trees.len()).collect();
+    tids.sort_by_key(|&tid| Reverse(lengths[tid]));
+    let jobs: Vec<Option<usize>> = tids.into_iter().map(Some).chain([None]).collect();
+    let next = AtomicUsize::new(0);
+    let budget = ScanBudget::new(paras);
+    let scanned = AtomicUsize::new(0); // 所有线程已汇总的record数量
+    let stop = AtomicBool::new(false); // 超出预算，所有线程停止
+    let results: Vec<Result<ReadStats, MyError>> = thread::scope(|s| {
+        let handles: Vec<_> = (0..paras.threads.min(jobs.len()).max(1)).map(|_| s.spawn(|| {
+            let mut reader = open()?;
+            let mut stats = ReadStats::new(trees.len());
+            let mut batch = 0;
+            // 依次取下一个还没处理的contig
+            while let Some(&job) = jobs.get(next.fetch_add(1, Ordering::Relaxed)) {
+                let mut sink = |record: &AlignmentRecord| {
+                    batch += 1;
+                    if batch == BUDGET_BATCH {
+                        batch = 0;
+                        if budget.exceeded(scanned.fetch_add(BUDGET_BATCH, Ordering::Relaxed) + BUDGET_BATCH) {
+                            stop.store(true, Ordering::Relaxed);
+                        }
+                    }
+                    if stop.load(Ordering::Relaxed) {
+                        return false
+                    }
+                    stats.records += 1;
+                    count_record(record, trees, paras, &mut stats);
+                    true
+                };
+                match job {
+                    Some(tid) => reader.for_each_record_in(tid, &mut sink)?,
+                    None => reader.for_each_unplaced(&mut sink)?,
                 }
-                let tmp_str = tmp.map(|s| s.data().clone()).collect::<HashSet<_>>().into_iter().collect::<Vec<String>>().join(":");
-                read_id_map_strand_gene_strand += &tmp_str;
-                match s_strandness.get_mut(&read_id_map_strand_gene_strand) {
-                    Some(s) => *s += 1.0,
-                    None => {
-                        s_strandness.insert(read_id_map_strand_gene_strand, 1.0);
-                    },
+                if stop.load(Ordering::Relaxed) {
+                    stats.budget_hit = true;
+                    break
                 }
-                s_strandness_sum += 1.0;
-                count += 1;
             }
+            Ok(stats)
+        })).collect();
+        handles.into_iter().map(|h| h.join().expect("counting thread panicked")).collect()
+    });
+    let mut stats = ReadStats::new(trees.len());
+    for r in results {
+        stats.merge(&r?);
+    }
+    Ok(stats)
+}
+
+/// 统计一条record，计入usable reads时count加1
+fn count_record(record: &AlignmentRecord, trees: &[Option<IntervalIndex>], paras: &ParsedParas, stats: &mut ReadStats) {
+    // skip low quanlity
+    if record.is_quality_check_failed() {
+        return
+    }
+    // skip duplicate read
+    if record.is_duplicate() {
+        return
+    }
+    // skip non primary hit
+    if record.is_secondary() {
+        return
+    }
+    // skip unmap read
+    if record.is_unmapped() {
+        return
+    }
+    // 舍弃质量分数低于自定义阈值的record
+    if record.mapq < paras.mapq {
+        return
+    }
+    // 统计
+    let mut is_paired = record.is_paired();
+    if is_paired && !record.is_first_in_template() && !record.is_last_in_template() {
+        stats.unknown_mate += 1;
+        if !paras.unknown_mate_as_single {
+            return
         }
+        is_paired = false; // 当作单端read统计
+    }
+    // 根据tid取当前record所在chr的IntervalIndex
+    let tid = record.tid as usize;
+    let tree = match trees.get(tid) {
+        Some(Some(tree)) => tree,
+        _ => return,
+    };
+    let read_start = record.pos as u64;
+    let read_end = read_start + record.seq_len as u64; // 这里加上原始read长度，比如150
+    // 比对到的所有feature的链信息集合
+    let strands = tree.strands(read_start, read_end);
+    if strands == 0 {
+        return
     }
+    let (first, reverse) = (record.is_first_in_template(), record.is_reverse());
+    let add = |counts: &mut StrandCounts| if is_paired {
+        counts.add_paired(first, reverse, strands)
+    } else {
+        counts.add_single(reverse, strands)
+    };
+    add(&mut stats.contigs[tid]);
+    // 计算了locus（按基因bootstrap）时，再记录到read所在的基因
+    if let Some(locus) = tree.locus(read_start, read_end) {
+        add(stats.genes.entry((tid, locus)).or_default());
+    }
+    stats.count += 1;
+}
 
-    //println!("p_keys: {:?}", p_strandness.keys());
-    //println!("s_keys: {:?}", s_strandness.keys());
-    // 最后统计，并打印结果
-    println!("Total {} usable reads were sampled", count);
-    let mut spec1: f64 = 0.0;
-    let mut spec1_each: [f64;4] = [0.0, 0.0, 0.0, 0.0];
-    let mut spec2: f64 = 0.0;
-    let mut spec2_each: [f64;4] = [0.0, 0.0, 0.0, 0.0];
-    let other: f64;
-    if p_strandness.len() > 0 && s_strandness.len() == 0 {
-        //spec1 = (p_strandness.get("1++").unwrap() + p_strandness.get("1--").unwrap() + p_strandness.get("2+-").unwrap() + p_strandness.get("2-+").unwrap()) / p_strandness_sum;
-        if let Some(v) = p_strandness.get("1++") {
-            spec1 += v;
-            spec1_each[0] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("1--") {
-            spec1 += v;
-            spec1_each[1] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("2+-") {
-            spec1 += v;
-            spec1_each[2] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("2-+") {
-            spec1 += v;
-            spec1_each[3] = v / p_strandness_sum;
-        }
-        spec1 /= p_strandness_sum;
-        //spec2 = (p_strandness.get("1+-").unwrap() + p_strandness.get("1-+").unwrap() + p_strandness.get("2++").unwrap() + p_strandness.get("2--").unwrap()) / p_strandness_sum;
-        if let Some(v) = p_strandness.get("1+-") {
-            spec2 += v;
-            spec2_each[0] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("1-+") {
-            spec2 += v;
-            spec2_each[1] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("2++") {
-            spec2 += v;
-            spec2_each[2] = v / p_strandness_sum;
-        }
-        if let Some(v) = p_strandness.get("2--") {
-            spec2 += v;
-            spec2_each[3] = v / p_strandness_sum;
-        }
-        spec2 /= p_strandness_sum;
-        other = if 1.0 - spec1 - spec2 < 0.0 {
-            0.0
-        } else {
-            1.0 - spec1 - spec2
-        };
-        println!("This is PairEnd Data");
-        println!("Fraction of reads failed to determine: {:.4}", other);
-        println!("Fraction of reads explained by \"1++,1--,2+-,2-+\": {:.4} ({:.4}, {:.4}, {:.4}, {:.4})", spec1, spec1_each[0], spec1_each[1], spec1_each[2], spec1_each[3]);
-        println!("Fraction of reads explained by \"1+-,1-+,2++,2--\": {:.4} ({:.4}, {:.4}, {:.4}, {:.4})", spec2, spec2_each[0], spec2_each[1], spec2_each[2], spec2_each[3]);
-    } else if s_strandness.len() > 0 && p_strandness.len() == 0 {
-        //spec1 = (s_strandness.get("++").unwrap() + s_strandness.get("--").unwrap()) / s_strandness_sum;
-        if let Some(v) = s_strandness.get("++") {
-            spec1 += v;
-            spec1_each[0] = v / s_strandness_sum;
-        }
-        if let Some(v) = s_strandness.get("--") {
-            spec1 += v;
-            spec1_each[1] = v / s_strandness_sum;
-        }
-        spec1 /= s_strandness_sum;
-        //spec2 = (s_strandness.get("+-").unwrap() + s_strandness.get("-+").unwrap()) / s_strandness_sum;
-        if let Some(v) = s_strandness.get("+-") {
-            spec2 += v;
-            spec2_each[0] = v / s_strandness_sum;
-        }
-        if let Some(v) = s_strandness.get("-+") {
-            spec2 += v;
-            spec2_each[1] = v / s_strandness_sum;
-        }
-        spec2 /= s_strandness_sum;
-        other = if 1.0 - spec1 - spec2 < 0.0 {
-            0.0
-        } else {
-            1.0 - spec1 - spec2
-        };
-        println!("This is SingleEnd Data");
-        println!("Fraction of reads failed to determine: {:.4}", other);
-        println!("Fraction of reads explained by \"++,--\": {:.4} ({:.4}, {:.4})", spec1, spec1_each[0], spec1_each[1]);
-        println!("Fraction of reads explained by \"+-,-+\": {:.4} ({:.4}, {:.4})", spec2, spec2_each[0], spec2_each[1]);
+/// 打印每个contig的统计结果，只打印有usable read的contig
+fn report_contigs(bam_contigs: &[String], stats: &ReadStats) {
+    println!("Per-contig counts (contig, PairEnd reads, SingleEnd reads, fraction explained by \"1++,1--,2+-,2-+\" or \"++,--\", fraction explained by \"1+-,1-+,2++,2--\" or \"+-,-+\", each followed by the lower and upper bounds of its Wilson 95% CI):");
+    for (chr, counts) in bam_contigs.iter().zip(&stats.contigs) {
+        let (p, s) = (counts.paired_sum(), counts.single_sum());
+        if p + s == 0 {
+            continue
+        }
+        let (spec1, spec2) = counts.explained();
+        let (lower1, upper1) = wilson_interval(spec1, p + s, Z_95);
+        let (lower2, upper2) = wilson_interval(spec2, p + s, Z_95);
+        println!("{}\t{}\t{}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}\t{:.4}", chr, p, s, spec1 as f64 / (p + s) as f64, lower1, upper1, spec2 as f64 / (p + s) as f64, lower2, upper2);
+    }
+}
+
+/// 占可解释read（两种链特异性之和）的比例不低于它时判断为链特异性
+const STRANDED_RATIO: f64 = 0.9;
+
+/// 两种链特异性各自占可解释read的比例与0.5相差不超过它时判断为非链特异性
+const UNSTRANDED_DEVIATION: f64 = 0.1;
+
+/// 根据选定的那部分read给出结论，两种链特异性都不占明显多数、又不接近各占一半时无法判断
+fn report_verdict(counts: &StrandCounts, use_paired: bool) {
+    let (label, classes, names) = if use_paired {
+        ("PairEnd", paired_classes(counts), ["1++,1--,2+-,2-+", "1+-,1-+,2++,2--"])
     } else {
-        println!("Unknown Data type");
+        ("SingleEnd", single_classes(counts), ["++,--", "+-,-+"])
+    };
+    let explained = classes[0] + classes[1];
+    if explained == 0 {
+        println!("Verdict ({}): undetermined, no reads were explained by either strandedness", label);
+        return
     }
-    Ok(())
+    let ratio = classes[0] as f64 / explained as f64;
+    if ratio >= STRANDED_RATIO {
+        println!("Verdict ({}): stranded, \"{}\"", label, names[0]);
+    } else if 1.0 - ratio >= STRANDED_RATIO {
+        println!("Verdict ({}): stranded, \"{}\"", label, names[1]);
+    } else if (ratio - 0.5).abs() <= UNSTRANDED_DEVIATION {
+        println!("Verdict ({}): unstranded", label);
+    } else {
+        println!("Verdict ({}): undetermined, neither strandedness is dominant", label);
+    }
+}
+
+/// 双端read的分类数量：1++,1--,2+-,2-+；1+-,1-+,2++,2--；只比对到无链信息feature；无法判断
+fn paired_classes(counts: &StrandCounts) -> [u64; 4] {
+    let p = &counts.paired;
+    let spec1 = p[0][0][ONLY_FORWARD] + p[0][1][ONLY_REVERSE] + p[1][0][ONLY_REVERSE] + p[1][1][ONLY_FORWARD];
+    let spec2 = p[0][0][ONLY_REVERSE] + p[0][1][ONLY_FORWARD] + p[1][0][ONLY_FORWARD] + p[1][1][ONLY_REVERSE];
+    let unstranded = p[0][0][ONLY_UNKNOWN] + p[0][1][ONLY_UNKNOWN] + p[1][0][ONLY_UNKNOWN] + p[1][1][ONLY_UNKNOWN];
+    [spec1, spec2, unstranded, counts.paired_sum() - spec1 - spec2 - unstranded]
+}
+
+/// 单端read的分类数量：++,--；+-,-+；只比对到无链信息feature；无法判断
+fn single_classes(counts: &StrandCounts) -> [u64; 4] {
+    let s = &counts.single;
+    let spec1 = s[0][ONLY_FORWARD] + s[1][ONLY_REVERSE];
+    let spec2 = s[0][ONLY_REVERSE] + s[1][ONLY_FORWARD];
+    let unstranded = s[0][ONLY_UNKNOWN] + s[1][ONLY_UNKNOWN];
+    [spec1, spec2, unstranded, counts.single_sum() - spec1 - spec2 - unstranded]
+}
+
+/// 每一类比例的置信区间文本，Wilson区间根据总数计算，bootstrap区间对每个单位（基因或contig）的分类数量重抽样
+fn class_intervals(units: &[StrandCounts], classes: fn(&StrandCounts) -> [u64; 4], bootstrap: Option<Bootstrap>) -> [String; 4] {
+    let units: Vec<[u64; 4]> = units.iter().map(classes).collect();
+    let mut total = [0u64; 4];
+    for unit in &units {
+        for (t, c) in total.iter_mut().zip(unit) {
+            *t += c;
+        }
+    }
+    let n: u64 = total.iter().sum();
+    let mut text = total.map(|k| {
+        let (lower, upper) = wilson_interval(k, n, Z_95);
+        format!(", 95% CI [{:.4}, {:.4}]", lower, upper)
+    });
+    if let Some(b) = bootstrap {
+        match bootstrap_intervals(&units, &b) {
+            Some(intervals) => {
+                for (t, (lower, upper)) in text.iter_mut().zip(intervals) {
+                    t.push_str(&format!(", bootstrap ({} {}) 95% CI [{:.4}, {:.4}]", b.replicates, b.unit, lower, upper));
+                }
+            },
+            // 只有按基因或contig抽样时才会出现
+            None => println!("Warning - bootstrap over {} needs at least 2 {} with usable reads, skipped", b.unit, b.unit),
+        }
+    }
+    text
 }
 
-/*
-// 通过flag是否含有64或128来判断1或2：https://github.com/rust-bio/rust-htslib/pull/440
-// 检查flag是否含有指定flag值，这段代码还没加到包中，因此写到这里进行调用
-// 代码参考自：https://github.com/rust-bio/rust-htslib/pull/440/commits/bfb2db10aa66beb494eb5993608282b1d83e9d98
-struct SamFlag;
-
-impl SamFlag {
-    //const PAIRED:          u16 = 1;
-    //const PROPERLY_PAIRED: u16 = 2;
-    //const READ_UNMAPPED:   u16 = 4;
-    //const MATE_UNMAPPED:   u16 = 8;
-    //const READ_RERVERSE:   u16 = 16;
-    //const MATE_REVERSE:    u16 = 32;
-    const FIRST_IN_PAIR:   u16 = 64;
-    const SECOND_IN_PAIR:  u16 = 128;
-    //const NOT_PRIMARY_ALN: u16 = 256;
-    //const FAIL_QC:         u16 = 512;
-    //const DUPLICATE:       u16 = 1024;
-    //const SUPPLEMENTARY:   u16 = 2048;
+/// 打印双端数据的统计结果
+fn report_paired(counts: &StrandCounts, units: &[StrandCounts], show_unstranded: bool, bootstrap: Option<Bootstrap>) {
+    let p = &counts.paired;
+    let p_strandness_sum = counts.paired_sum() as f64;
+    // 1++,1--,2+-,2-+
+    let spec1_each: [f64;4] = [p[0][0][ONLY_FORWARD], p[0][1][ONLY_REVERSE], p[1][0][ONLY_REVERSE], p[1][1][ONLY_FORWARD]].map(|v| v as f64 / p_strandness_sum);
+    // 1+-,1-+,2++,2--
+    let spec2_each: [f64;4] = [p[0][0][ONLY_REVERSE], p[0][1][ONLY_FORWARD], p[1][0][ONLY_FORWARD], p[1][1][ONLY_REVERSE]].map(|v| v as f64 / p_strandness_sum);
+    let spec1: f64 = spec1_each.iter().sum();
+    let spec2: f64 = spec2_each.iter().sum();
+    // 只比对到无链信息feature的read，1+.,1-.,2+.,2-.
+    let spec_unstranded = (p[0][0][ONLY_UNKNOWN] + p[0][1][ONLY_UNKNOWN] + p[1][0][ONLY_UNKNOWN] + p[1][1][ONLY_UNKNOWN]) as f64 / p_strandness_sum;
+    let other = if 1.0 - spec1 - spec2 - spec_unstranded < 0.0 {
+        0.0
+    } else {
+        1.0 - spec1 - spec2 - spec_unstranded
+    };
+    let ci = class_intervals(units, paired_classes, bootstrap);
+    println!("Fraction of reads failed to determine: {:.4}{}", other, ci[3]);
+    if show_unstranded {
+        println!("Fraction of reads overlapping only unstranded features: {:.4}{}", spec_unstranded, ci[2]);
+    }
+    println!("Fraction of reads explained by \"1++,1--,2+-,2-+\": {:.4} ({:.4}, {:.4}, {:.4}, {:.4}){}", spec1, spec1_each[0], spec1_each[1], spec1_each[2], spec1_each[3], ci[0]);
+    println!("Fraction of reads explained by \"1+-,1-+,2++,2--\": {:.4} ({:.4}, {:.4}, {:.4}, {:.4}){}", spec2, spec2_each[0], spec2_each[1], spec2_each[2], spec2_each[3], ci[1]);
+}
+
+/// 打印单端数据的统计结果
+fn report_single(counts: &StrandCounts, units: &[StrandCounts], show_unstranded: bool, bootstrap: Option<Bootstrap>) {
+    let s = &counts.single;
+    let s_strandness_sum = counts.single_sum() as f64;
+    // ++,--
+    let spec1_each: [f64;2] = [s[0][ONLY_FORWARD], s[1][ONLY_REVERSE]].map(|v| v as f64 / s_strandness_sum);
+    // +-,-+
+    let spec2_each: [f64;2] = [s[0][ONLY_REVERSE], s[1][ONLY_FORWARD]].map(|v| v as f64 / s_strandness_sum);
+    let spec1: f64 = spec1_each.iter().sum();
+    let spec2: f64 = spec2_each.iter().sum();
+    // 只比对到无链信息feature的read，+.,-.
+    let spec_unstranded = (s[0][ONLY_UNKNOWN] + s[1][ONLY_UNKNOWN]) as f64 / s_strandness_sum;
+    let other = if 1.0 - spec1 - spec2 - spec_unstranded < 0.0 {
+        0.0
+    } else {
+        1.0 - spec1 - spec2 - spec_unstranded
+    };
+    let ci = class_intervals(units, single_classes, bootstrap);
+    println!("Fraction of reads failed to determine: {:.4}{}", other, ci[3]);
+    if show_unstranded {
+        println!("Fraction of reads overlapping only unstranded features: {:.4}{}", spec_unstranded, ci[2]);
+    }
+    println!("Fraction of reads explained by \"++,--\": {:.4} ({:.4}, {:.4}){}", spec1, spec1_each[0], spec1_each[1], ci[0]);
+    println!("Fraction of reads explained by \"+-,-+\": {:.4} ({:.4}, {:.4}){}", spec2, spec2_each[0], spec2_each[1], ci[1]);
 }
 
-/// This function uses bitwise operations to test if flags are set
-/// # Arguments
-/// * `flag`: u16 - The record flag you want to test
-/// * `in_`: u16 - The flags you want to check if they are set (use 0 for no test)
-/// # Usage:
-/// let test if a flag is both paired and first in pair
-/// ```
-/// use rust_htslib::bam::flags::{Flag, is_in_flag};
-/// let read_flag = 65;
-/// assert_eq!(is_in_flag(read_flag, Flag::PAIRED + Flag::FIRST_IN_PAIR), true);
-/// ```
-fn is_in_flag(flag: u16, in_: u16) -> bool {
-    if (in_ & flag) != in_ {
-        return false;
-    }
-    true
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+    #[cfg(feature = "htslib")]
+    use std::fmt::Write as _;
+    use std::fs;
+    use std::path::PathBuf;
+    #[cfg(feature = "htslib")]
+    use std::path::Path;
+
+    #[cfg(feature = "htslib")]
+    use rust_htslib::bam::{self, Read as _};
+
+    use crate::{
+        alignment::AlignmentHeader,
+        annotation::{AnnotationFormat, FeatureStrand, ParseMode},
+        bed::BedSource,
+        contig::AssemblyCheck,
+        parse_paras::AnnotationParas,
+    };
+
+    /// 内存中的record，同时作为顺序读取和按contig读取的reader，不需要htslib写入bam
+    #[derive(Clone)]
+    struct MockReader {
+        header:  AlignmentHeader,
+        records: Vec<AlignmentRecord>,
+    }
+
+    impl MockReader {
+        /// 依次将满足条件的record传给sink
+        fn replay(&self, keep: impl Fn(&AlignmentRecord) -> bool, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+            for record in self.records.iter().filter(|r| keep(r)) {
+                if !sink(record) {
+                    break
+                }
+            }
+            Ok(())
+        }
+    }
+
+    impl AlignmentReader for MockReader {
+        fn header(&self) -> &AlignmentHeader {
+            &self.header
+        }
+
+        fn for_each_record(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+            self.replay(|_| true, sink)
+        }
+    }
+
+    impl IndexedAlignmentReader for MockReader {
+        fn for_each_record_in(&mut self, tid: usize, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+            self.replay(|r| r.tid == tid as i32, sink)
+        }
+
+        fn for_each_unplaced(&mut self, sink: &mut dyn FnMut(&AlignmentRecord) -> bool) -> Result<(), MyError> {
+            self.replay(|r| r.tid < 0, sink)
+        }
+    }
+
+    /// 与write_sorted_bam相同的read，最后是没有比对位置的read
+    fn mock_reader() -> MockReader {
+        let contigs = [("chr1", 40000), ("chr2", 30000), ("chr3", 5000)];
+        let flags = [0, 16, 99, 147, 83, 163, 1, 17, 4, 1024, 256, 65, 129];
+        let mut records = Vec::new();
+        for (tid, (_, len)) in contigs.iter().enumerate() {
+            for pos in (0..len - 51).step_by(37) {
+                let i = records.len();
+                records.push(AlignmentRecord{tid: tid as i32, pos, mapq: if i % 7 == 0 { 10 } else { 60 }, flags: flags[i % flags.len()], seq_len: 50});
+            }
+        }
+        for _ in 0..25 {
+            records.push(AlignmentRecord{tid: -1, pos: -1, mapq: 0, flags: 4, seq_len: 50});
+        }
+        MockReader{
+            header: AlignmentHeader{names: contigs.iter().map(|(n, _)| n.to_string()).collect(), lengths: contigs.iter().map(|(_, l)| *l as u64).collect()},
+            records,
+        }
+    }
+
+    /// 测试用的临时文件，按测试名区分，避免并行测试互相覆盖
+    fn temp_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("infer_experiment_infer_{}_{}", std::process::id(), name))
+    }
+
+    fn test_paras(input_file: PathBuf, threads: usize) -> ParsedParas {
+        ParsedParas{
+            input_file,
+            annotation: AnnotationParas{
+                refgene: None,
+                annotation_format: AnnotationFormat::Auto,
+                gtf: None,
+                gff3: None,
+                feature: "gene".to_string(),
+                gtf_attr: Vec::new(),
+                parse_mode: ParseMode::Strict,
+                exclude_intronic: false,
+                given: Vec::new(),
+                verify_cache: false,
+            },
+            sample_size: 200000,
+            mapq: 30,
+            unstranded: UnstrandedMode::Separate,
+            layout: Layout::Majority,
+            unknown_mate_as_single: false,
+            chr_alias: None,
+            assembly_check: AssemblyCheck::Off,
+            threads,
+            whole_file: true,
+            per_contig: false,
+            counts_out: None,
+            ci_width: None,
+            min_reads: 5000,
+            max_records: None,
+            max_seconds: None,
+            bootstrap: None,
+        }
+    }
+
+    /// 按坐标排序的bam，包含单端、双端、不明确mate、低mapq、重复、secondary、未比对和没有比对位置的read，chr3没有注释
+    #[cfg(feature = "htslib")]
+    fn write_sorted_bam(file: &Path) {
+        let contigs = [("chr1", 40000), ("chr2", 30000), ("chr3", 5000)];
+        let flags = [0, 16, 99, 147, 83, 163, 1, 17, 4, 1024, 256, 65, 129];
+        let mut sam = String::new();
+        for (name, len) in contigs {
+            writeln!(sam, "@SQ\tSN:{}\tLN:{}", name, len).unwrap();
+        }
+        let mut i = 0;
+        for (name, len) in contigs {
+            for pos in (1..len - 50).step_by(37) {
+                let mapq = if i % 7 == 0 { 10 } else { 60 };
+                writeln!(sam, "r{}\t{}\t{}\t{}\t{}\t50M\t*\t0\t0\t{}\t*", i, flags[i % flags.len()], name, pos, mapq, "A".repeat(50)).unwrap();
+                i += 1;
+            }
+        }
+        for j in 0..25 {
+            writeln!(sam, "u{}\t4\t*\t0\t0\t*\t*\t0\t0\t{}\t*", j, "A".repeat(50)).unwrap();
+        }
+        let sam_file = file.with_extension("sam");
+        fs::write(&sam_file, sam).unwrap();
+        let mut reader = bam::Reader::from_path(&sam_file).unwrap();
+        let header = bam::Header::from_template(reader.header());
+        let mut writer = bam::Writer::from_path(file, &header, bam::Format::Bam).unwrap();
+        for record in reader.records() {
+            writer.write(&record.unwrap()).unwrap();
+        }
+        fs::remove_file(sam_file).unwrap();
+    }
+
+    fn test_trees() -> Vec<Option<IntervalIndex>> {
+        vec![
+            Some(IntervalIndex::new(vec![
+                (1000, 9000, FeatureStrand::Forward),
+                (8000, 16000, FeatureStrand::Reverse),
+                (20000, 26000, FeatureStrand::Unknown),
+                (30000, 38000, FeatureStrand::Reverse),
+            ])),
+            Some(IntervalIndex::new(vec![
+                (0, 12000, FeatureStrand::Reverse),
+                (15000, 29000, FeatureStrand::Forward),
+            ])),
+            None,
+        ]
+    }
+
+    #[test]
+    fn parallel_counts_unplaced_reads() {
+        let mock = mock_reader();
+        let trees = test_trees();
+        let paras = test_paras(PathBuf::from("mock.bam"), 1);
+        let expected = count_sequential(&mut mock.clone(), &trees, &paras).unwrap();
+        assert_eq!(expected.records, mock.records.len());
+        assert!(expected.count > 0 && expected.unknown_mate > 0);
+        for threads in [1, 2, 3, 4, 8] {
+            let open = || Ok(Box::new(mock.clone()) as Box<dyn IndexedAlignmentReader>);
+            let stats = count_parallel(&open, &trees, &mock.header.lengths, &test_paras(PathBuf::from("mock.bam"), threads)).unwrap();
+            assert_eq!(stats.contigs, expected.contigs, "threads = {}", threads);
+            assert_eq!((stats.count, stats.unknown_mate, stats.records, stats.budget_hit), (expected.count, expected.unknown_mate, expected.records, expected.budget_hit));
+        }
+    }
+
+    #[cfg(feature = "htslib")]
+    #[test]
+    fn parallel_matches_sequential() {
+        let bam_file = temp_file("parallel.bam");
+        write_sorted_bam(&bam_file);
+        // 没有索引时退回单线程
+        assert_eq!(find_index(&bam_file), None);
+        bam::index::build(&bam_file, None, bam::index::Type::Bai, 1).unwrap();
+        let mut index = bam_file.as_os_str().to_owned();
+        index.push(".bai");
+        assert_eq!(find_index(&bam_file), Some(PathBuf::from(&index)));
+
+        let trees = test_trees();
+        let lengths = [40000, 30000, 5000];
+        let mut reader = open_reader(&bam_file, 1).unwrap();
+        let expected = count_sequential(reader.as_mut(), &trees, &test_paras(bam_file.clone(), 1)).unwrap();
+        assert!(expected.count > 0 && expected.unknown_mate > 0);
+        for threads in [2, 3, 4] {
+            let stats = count_parallel(&|| open_indexed(&bam_file), &trees, &lengths, &test_paras(bam_file.clone(), threads)).unwrap();
+            assert_eq!(stats.contigs, expected.contigs, "threads = {}", threads);
+            assert_eq!((stats.count, stats.unknown_mate, stats.records, stats.budget_hit), (expected.count, expected.unknown_mate, expected.records, expected.budget_hit));
+        }
+        fs::remove_file(&bam_file).unwrap();
+        fs::remove_file(&index).unwrap();
+    }
+
+    /// 同一套注释中混用RefSeq（NC_000001.11）、Ensembl（2、MT）和UCSC（chr1）的contig名称，还有小鼠的accession和bam中没有的scaffold
+    const RENAMED_BED: &str = "\
+NC_000001.11\t1000\t2000\tg1\t0\t+\n\
+chr1\t3000\t4000\tg2\t0\t-\n\
+2\t5000\t6000\tg3\t0\t-\n\
+MT\t100\t500\tg4\t0\t+\n\
+NC_000067.7\t1000\t2000\tmouse\t0\t+\n\
+chrUn_KI270302v1\t100\t200\tu1\t0\t+\n\
+chrUn_KI270302v1\t300\t400\tu2\t0\t+\n";
+
+    /// 按contigs加载RENAMED_BED，与run_infer一样按bam的tid构建IntervalIndex，再统计1号染色体上的两条、2号染色体和线粒体上各一条单端read
+    fn load_and_count(name: &str, header: &AlignmentHeader, contigs: &ContigFilter) -> (GeneRanges, ReadStats) {
+        let bed = temp_file(name);
+        fs::write(&bed, RENAMED_BED).unwrap();
+        let ranges = GeneRanges::from_source(&BedSource{path: bed.clone(), parse_mode: ParseMode::Strict, threads: 1}, UnstrandedMode::Separate, false, Some(contigs)).unwrap();
+        fs::remove_file(&bed).unwrap();
+        let trees: Vec<Option<IntervalIndex>> = header.names.iter().map(|chr| ranges.ranges.get(chr).map(|iv| IntervalIndex::new(iv.clone()))).collect();
+        let record = |tid: i32, pos: i64, flags: u16| AlignmentRecord{tid, pos, mapq: 60, flags, seq_len: 50};
+        let mut reader = MockReader{header: header.clone(), records: vec![record(0, 1100, 0), record(0, 3100, 16), record(1, 5100, 0), record(2, 200, 16)]};
+        let stats = count_sequential(&mut reader, &trees, &test_paras(PathBuf::from("renamed.bam"), 1)).unwrap();
+        (ranges, stats)
+    }
+
+    /// 两种命名的bam统计结果一致：1号染色体上+链和-链feature各一条，2号染色体上-链feature一条，线粒体上+链feature一条
+    fn assert_counted_under_bam_names(ranges: &GeneRanges, stats: &ReadStats, names: &[String]) {
+        let mut loaded: Vec<&String> = ranges.ranges.keys().collect();
+        loaded.sort_unstable();
+        let mut expected: Vec<&String> = names.iter().collect();
+        expected.sort_unstable();
+        assert_eq!(loaded, expected);
+        assert_eq!(ranges.features, 4);
+        assert_eq!(ranges.unused_contigs, BTreeMap::from([("NC_000067.7".to_string(), 1), ("chrUn_KI270302v1".to_string(), 2)]));
+        assert_eq!(stats.count, 4);
+        assert_eq!((stats.contigs[0].single[0][ONLY_FORWARD], stats.contigs[0].single[1][ONLY_REVERSE]), (1, 1));
+        assert_eq!(stats.contigs[1].single[0][ONLY_REVERSE], 1);
+        assert_eq!(stats.contigs[2].single[1][ONLY_FORWARD], 1);
+    }
+
+    #[test]
+    fn renamed_contigs_counted_under_bam_names() {
+        let lengths = vec![248956422, 242193529, 16569];
+        // UCSC命名的GRCh38 bam
+        let header = AlignmentHeader{names: vec!["chr1".to_string(), "chr2".to_string(), "chrM".to_string()], lengths: lengths.clone()};
+        let (ranges, stats) = load_and_count("renamed_ucsc.bed", &header, &ContigFilter::new(&header.names, &header.lengths));
+        assert_eq!(ranges.renamed_contigs, BTreeMap::from([
+            ("2".to_string(), "chr2".to_string()),
+            ("MT".to_string(), "chrM".to_string()),
+            ("NC_000001.11".to_string(), "chr1".to_string()),
+        ]));
+        assert_counted_under_bam_names(&ranges, &stats, &header.names);
+        // Ensembl命名的GRCh38 bam
+        let header = AlignmentHeader{names: vec!["1".to_string(), "2".to_string(), "MT".to_string()], lengths};
+        let (ranges, stats) = load_and_count("renamed_ensembl.bed", &header, &ContigFilter::new(&header.names, &header.lengths));
+        assert_eq!(ranges.renamed_contigs, BTreeMap::from([
+            ("NC_000001.11".to_string(), "1".to_string()),
+            ("chr1".to_string(), "1".to_string()),
+        ]));
+        assert_counted_under_bam_names(&ranges, &stats, &header.names);
+    }
+
+    #[test]
+    fn alias_table_maps_genbank_names() {
+        // GenBank accession命名的GRCh38 bam，无法判断物种，也不能自动转换
+        let header = AlignmentHeader{names: vec!["CM000663.2".to_string(), "CM000664.2".to_string(), "J01415.2".to_string()], lengths: vec![248956422, 242193529, 16569]};
+        let contigs = ContigFilter::new(&header.names, &header.lengths);
+        let (ranges, stats) = load_and_count("alias_none.bed", &header, &contigs);
+        assert!(ranges.ranges.is_empty() && stats.count == 0);
+        assert_eq!(ranges.unused_contigs.values().sum::<usize>(), 7);
+        // NCBI assembly report取Sequence-Name、GenBank-Accn、RefSeq-Accn、UCSC-style-name，bam中没有的scaffold不产生别名
+        let report = "# Assembly name:  GRCh38.p14\n\
+                      # Sequence-Name\tSequence-Role\tAssigned-Molecule\tAssigned-Molecule-Location/Type\tGenBank-Accn\tRelationship\tRefSeq-Accn\tAssembly-Unit\tSequence-Length\tUCSC-style-name\n\
+                      1\tassembled-molecule\t1\tChromosome\tCM000663.2\t=\tNC_000001.11\tPrimary Assembly\t248956422\tchr1\n\
+                      2\tassembled-molecule\t2\tChromosome\tCM000664.2\t=\tNC_000002.12\tPrimary Assembly\t242193529\tchr2\n\
+                      MT\tassembled-molecule\tMT\tMitochondrion\tJ01415.2\t=\tNC_012920.1\tnon-nuclear\t16569\tchrM\n\
+                      HSCHRUN_RANDOM_CTG1\tunplaced-scaffold\tna\tna\tKI270302.1\t=\tNT_187395.1\tPrimary Assembly\t2274\tchrUn_KI270302v1\n";
+        // 两列的tsv
+        let tsv = "chr1\tCM000663.2\nNC_000001.11\tCM000663.2\n2\tCM000664.2\nMT\tJ01415.2\n";
+        for (name, table) in [("assembly_report.txt", report), ("alias.tsv", tsv)] {
+            let file = temp_file(name);
+            fs::write(&file, table).unwrap();
+            let mut contigs = ContigFilter::new(&header.names, &header.lengths);
+            contigs.add_aliases(read_alias_table(&file).unwrap());
+            fs::remove_file(&file).unwrap();
+            let (ranges, stats) = load_and_count(&format!("{}.bed", name), &header, &contigs);
+            assert_eq!(ranges.renamed_contigs, BTreeMap::from([
+                ("2".to_string(), "CM000664.2".to_string()),
+                ("MT".to_string(), "J01415.2".to_string()),
+                ("NC_000001.11".to_string(), "CM000663.2".to_string()),
+                ("chr1".to_string(), "CM000663.2".to_string()),
+            ]), "{}", name);
+            assert_counted_under_bam_names(&ranges, &stats, &header.names);
+        }
+    }
+
+    /// 一条contig上n条单端read，都落在正链feature上，每20条中1条比对到负链，占多数的比例为0.95
+    /// unmapped_every不为0时，每unmapped_every条read前插入1条未比对的read
+    fn strand_reader(n: usize, unmapped_every: usize) -> (MockReader, Vec<Option<IntervalIndex>>) {
+        let mut records = Vec::new();
+        for i in 0..n {
+            if unmapped_every > 0 && i % unmapped_every == 0 {
+                records.push(AlignmentRecord{tid: 0, pos: i as i64, mapq: 0, flags: 4, seq_len: 50});
+            }
+            records.push(AlignmentRecord{tid: 0, pos: i as i64, mapq: 60, flags: if i % 20 == 0 { 16 } else { 0 }, seq_len: 50});
+        }
+        let reader = MockReader{header: AlignmentHeader{names: vec!["chr1".to_string()], lengths: vec![1_000_000]}, records};
+        (reader, vec![Some(IntervalIndex::new(vec![(0, 1_000_000, FeatureStrand::Forward)]))])
+    }
+
+    /// 按--ci-width和--min-reads扫描strand_reader的read
+    fn scan_until_converged(n: usize, ci_width: f64, min_reads: usize) -> ReadStats {
+        let (mut reader, trees) = strand_reader(n, 0);
+        let mut paras = test_paras(PathBuf::from("mock.bam"), 1);
+        paras.ci_width = Some(ci_width);
+        paras.min_reads = min_reads;
+        count_sequential(&mut reader, &trees, &paras).unwrap()
+    }
+
+    #[test]
+    fn early_stop_when_interval_is_narrow() {
+        // 1000条时置信区间宽度约0.0272，2000条时约0.0192，每CHECK_INTERVAL条检查一次
+        let stats = scan_until_converged(20000, 0.02, 1000);
+        assert_eq!((stats.count, stats.records, stats.budget_hit), (2 * CHECK_INTERVAL, 2 * CHECK_INTERVAL, false));
+        let (lower, upper) = dominant_interval(&stats, Layout::Majority);
+        assert!(upper - lower < 0.02 && lower < 0.95 && 0.95 < upper);
+        // 至少统计--min-reads条才检查
+        assert_eq!(scan_until_converged(20000, 0.02, 5000).count, 5000);
+        // --min-reads小于CHECK_INTERVAL时第一次在CHECK_INTERVAL条时检查
+        assert_eq!(scan_until_converged(20000, 1.0, 10).count, CHECK_INTERVAL);
+        // 不收敛时读完所有read
+        assert_eq!(scan_until_converged(20000, 0.001, 1000).count, 20000);
+        // 没有--ci-width时不提前停止
+        let (mut reader, trees) = strand_reader(20000, 0);
+        assert_eq!(count_sequential(&mut reader, &trees, &test_paras(PathBuf::from("mock.bam"), 1)).unwrap().count, 20000);
+    }
+
+    #[test]
+    fn scan_budget_exceeded() {
+        let budget = ScanBudget{max_records: 10, deadline: None};
+        assert!(!budget.exceeded(9) && budget.exceeded(10) && budget.exceeded(11));
+        // 时间只在BUDGET_BATCH的整数倍时检查
+        let expired = ScanBudget{max_records: usize::MAX, deadline: Some(Instant::now())};
+        assert!(!expired.exceeded(1) && !expired.exceeded(BUDGET_BATCH + 1));
+        assert!(expired.exceeded(0) && expired.exceeded(BUDGET_BATCH));
+    }
+
+    #[test]
+    fn max_records_stops_the_scan() {
+        // 每4条usable read前有1条未比对的read，--max-records按读取的record计数，包括被过滤的
+        let (mut reader, trees) = strand_reader(20000, 4);
+        let mut paras = test_paras(PathBuf::from("mock.bam"), 1);
+        paras.max_records = Some(1500);
+        let stats = count_sequential(&mut reader.clone(), &trees, &paras).unwrap();
+        assert_eq!((stats.records, stats.count, stats.budget_hit), (1500, 1200, true));
+        // 在收敛之前超出预算时，结果标记为不完整
+        paras.ci_width = Some(0.02);
+        paras.min_reads = 1000;
+        let stats = count_sequential(&mut reader.clone(), &trees, &paras).unwrap();
+        assert_eq!((stats.records, stats.count, stats.budget_hit), (1500, 1200, true));
+        // 预算足够时读完所有record
+        paras.ci_width = None;
+        paras.max_records = Some(25000);
+        let stats = count_sequential(&mut reader, &trees, &paras).unwrap();
+        assert_eq!((stats.records, stats.count, stats.budget_hit), (25000, 20000, false));
+    }
+
+    #[test]
+    fn max_seconds_stops_the_scan() {
+        // 已经超时，第一次检查时间时就停止
+        let (mut reader, trees) = strand_reader(20000, 0);
+        let mut paras = test_paras(PathBuf::from("mock.bam"), 1);
+        paras.max_seconds = Some(0.0);
+        let stats = count_sequential(&mut reader, &trees, &paras).unwrap();
+        assert_eq!((stats.records, stats.count, stats.budget_hit), (0, 0, true));
+    }
+
+    #[test]
+    fn parallel_budget_is_approximate() {
+        // 多线程时每BUDGET_BATCH条汇总一次，最多多读取线程数 * BUDGET_BATCH条
+        let (reader, trees) = strand_reader(20000, 4);
+        let mut paras = test_paras(PathBuf::from("mock.bam"), 2);
+        paras.max_records = Some(3000);
+        let open = || Ok(Box::new(reader.clone()) as Box<dyn IndexedAlignmentReader>);
+        let stats = count_parallel(&open, &trees, &reader.header.lengths, &paras).unwrap();
+        assert!(stats.budget_hit);
+        assert!(stats.records >= 3000 - BUDGET_BATCH && stats.records <= 3000 + 2 * BUDGET_BATCH, "{}", stats.records);
+    }
 }
-*/
@@ -1,6 +1,19 @@
+pub mod alignment;
+pub mod annotation;
+#[cfg(feature = "htslib")]
+pub mod bam_htslib;
+#[cfg(feature = "noodles")]
+pub mod bam_noodles;
 pub mod bed;
+pub mod cache;
+pub mod contig;
+pub mod counts;
 pub mod error;
+pub mod genepred;
+pub mod gff3;
 pub mod gtf;
 pub mod infer;
+pub mod overlap;
 pub mod parse_paras;
+pub mod stats;
 pub mod utils;
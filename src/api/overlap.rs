@@ -0,0 +1,282 @@
+use crate::annotation::FeatureStrand;
+
+/// 排序后存储的一个区间，strand的bit（FeatureStrand::bit）放在end的低3位，max_end为以它为根的子树中最大的end
+#[derive(Debug, Clone, Copy)]
+struct PackedInterval {
+    start:      u64,
+    end_strand: u64, // end << 3 | strand bit
+    max_end:    u64,
+}
+
+impl PackedInterval {
+    fn end(&self) -> u64 {
+        self.end_strand >> 3
+    }
+
+    fn strand_bit(&self) -> u8 {
+        (self.end_strand & 0b111) as u8
+    }
+}
+
+/// 一条contig上的不可变区间索引，用于替代rust-bio的IntervalTree
+/// 区间按start排序存放在一个数组中，数组本身作为隐式的平衡二叉树（叶子在偶数下标，第k层节点在下标(2^k - 1) + i * 2^(k+1)），
+/// 每个节点记录子树中最大的end，查询时跳过max_end不超过查询start的子树，查询过程不分配内存
+/// 参考cgranges：https://github.com/lh3/cgranges
+#[derive(Debug, Clone, Default)]
+pub struct IntervalIndex {
+    intervals: Vec<PackedInterval>,
+    root_k:    u32,      // 根节点所在的层
+    loci:      Vec<u32>, // 每个区间所属的locus，只有按基因bootstrap时才计算（with_loci），否则为空
+}
+
+/// 查询时栈中的节点
+#[derive(Clone, Copy, Default)]
+struct StackItem {
+    k:    u32,   // 所在的层
+    x:    usize, // 下标
+    left: bool,  // 左子树是否已经处理
+}
+
+impl IntervalIndex {
+    /// 从(start, end, strand)构建，区间为左闭右开，end小于start的区间应该在构建前排除
+    pub fn new(mut intervals: Vec<(u64, u64, FeatureStrand)>) -> Self {
+        intervals.sort_unstable_by_key(|(s, e, _)| (*s, *e));
+        let mut a: Vec<PackedInterval> = intervals.into_iter().map(|(start, end, strand)| PackedInterval{
+            start,
+            end_strand: end << 3 | strand.bit() as u64,
+            max_end: end,
+        }).collect();
+        let n = a.len();
+        if n == 0 {
+            return IntervalIndex{intervals: a, root_k: 0, loci: Vec::new()}
+        }
+        // 自底向上计算每层节点的max_end，last_i、last记录最后一个存在的节点，用于右子节点超出数组的情况
+        let mut last_i = 0;
+        let mut last = 0;
+        for (i, iv) in a.iter().enumerate().step_by(2) {
+            last_i = i;
+            last = iv.max_end;
+        }
+        let mut k = 1;
+        while 1usize << k <= n {
+            let x = 1usize << (k - 1);
+            let mut i = (x << 1) - 1;
+            while i < n {
+                let el = a[i - x].max_end;
+                let er = if i + x < n { a[i + x].max_end } else { last };
+                a[i].max_end = a[i].end().max(el).max(er);
+                i += x << 2;
+            }
+            // last_i移到父节点：右子节点的父节点在左边，左子节点的父节点在右边
+            last_i = if (last_i >> k) & 1 == 1 { last_i - x } else { last_i + x };
+            if last_i < n {
+                last = a[last_i].max_end;
+            }
+            k += 1;
+        }
+        IntervalIndex{intervals: a, root_k: k - 1, loci: Vec::new()}
+    }
+
+    /// 计算每个区间所属的locus：按start排序后直接或间接重叠的区间属于同一个locus，不区分链，近似为基因
+    pub fn with_loci(mut self) -> Self {
+        let mut loci: Vec<u32> = Vec::with_capacity(self.intervals.len());
+        let mut locus = 0;
+        let mut reach = 0; // 当前locus中最大的end
+        for (i, iv) in self.intervals.iter().enumerate() {
+            if i > 0 && iv.start >= reach {
+                locus += 1;
+            }
+            reach = reach.max(iv.end());
+            loci.push(locus);
+        }
+        self.loci = loci;
+        self
+    }
+
+    /// 区间数量
+    pub fn len(&self) -> usize {
+        self.intervals.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.intervals.is_empty()
+    }
+
+    /// 对与[start, end)重叠的每个区间调用f，参数为区间的start、end和strand bit
+    pub fn for_each_overlap(&self, start: u64, end: u64, mut f: impl FnMut(u64, u64, u8)) {
+        self.for_each_overlap_index(start, end, |i| {
+            let iv = &self.intervals[i];
+            f(iv.start, iv.end(), iv.strand_bit())
+        });
+    }
+
+    /// 对与[start, end)重叠的每个区间调用f，参数为区间在数组中的下标
+    fn for_each_overlap_index(&self, start: u64, end: u64, mut f: impl FnMut(usize)) {
+        let a = &self.intervals;
+        let n = a.len();
+        if n == 0 {
+            return
+        }
+        // 树高不超过64层，用固定大小的栈
+        let mut stack = [StackItem::default(); 64];
+        let mut t = 0;
+        stack[t] = StackItem{k: self.root_k, x: (1usize << self.root_k) - 1, left: false};
+        t += 1;
+        while t > 0 {
+            t -= 1;
+            let z = stack[t];
+            if z.k <= 3 {
+                // 子树较小时直接线性扫描
+                let i0 = z.x >> z.k << z.k;
+                let i1 = (i0 + (1usize << (z.k + 1)) - 1).min(n);
+                for (i, iv) in a[i0.min(n)..i1].iter().enumerate().take_while(|(_, iv)| iv.start < end) {
+                    if start < iv.end() {
+                        f(i0 + i);
+                    }
+                }
+            } else if !z.left {
+                // 先重新压入当前节点并标记左子树已处理，左子树可能有重叠时再压入左子节点
+                let y = z.x - (1usize << (z.k - 1));
+                stack[t] = StackItem{k: z.k, x: z.x, left: true};
+                t += 1;
+                if y >= n || a[y].max_end > start {
+                    stack[t] = StackItem{k: z.k - 1, x: y, left: false};
+                    t += 1;
+                }
+            } else if z.x < n && a[z.x].start < end {
+                if start < a[z.x].end() {
+                    f(z.x);
+                }
+                // 右子树
+                stack[t] = StackItem{k: z.k - 1, x: z.x + (1usize << (z.k - 1)), left: false};
+                t += 1;
+            }
+        }
+    }
+
+    /// 与[start, end)重叠的所有区间的strand bit按位或，没有重叠时为0
+    pub fn strands(&self, start: u64, end: u64) -> u8 {
+        let mut strands: u8 = 0;
+        self.for_each_overlap(start, end, |_, _, s| strands |= s);
+        strands
+    }
+
+    /// 与[start, end)重叠的区间所属的locus，重叠多个locus时取最靠前的，没有重叠或没有计算locus时为None
+    pub fn locus(&self, start: u64, end: u64) -> Option<u32> {
+        if self.loci.is_empty() {
+            return None
+        }
+        let mut locus: Option<u32> = None;
+        self.for_each_overlap_index(start, end, |i| locus = Some(locus.map_or(self.loci[i], |l| l.min(self.loci[i]))));
+        locus
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 测试用的简单伪随机数（xorshift64）
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    fn random_strand(rng: &mut Rng) -> FeatureStrand {
+        [FeatureStrand::Forward, FeatureStrand::Reverse, FeatureStrand::Unknown][rng.below(3) as usize]
+    }
+
+    /// 随机区间，包括长度为0、互相嵌套和重复的区间
+    fn random_intervals(n: usize, rng: &mut Rng) -> Vec<(u64, u64, FeatureStrand)> {
+        (0..n).map(|_| {
+            let start = rng.below(2000);
+            let len = match rng.below(10) {
+                0 => 0,
+                1 => rng.below(1500),
+                _ => rng.below(50) + 1,
+            };
+            (start, start + len, random_strand(rng))
+        }).collect()
+    }
+
+    /// 一层套一层的区间
+    fn nested_intervals(n: usize, rng: &mut Rng) -> Vec<(u64, u64, FeatureStrand)> {
+        (0..n as u64).map(|i| (i * 3, 10000 - i * 3, random_strand(rng))).collect()
+    }
+
+    /// 逐个检查每个区间，重叠的定义与IntervalIndex一致：start < 区间end 且 区间start < end
+    fn brute_force(intervals: &[(u64, u64, FeatureStrand)], start: u64, end: u64) -> Vec<(u64, u64, u8)> {
+        let mut hits: Vec<(u64, u64, u8)> = intervals.iter().filter(|(s, e, _)| start < *e && *s < end).map(|(s, e, strand)| (*s, *e, strand.bit())).collect();
+        hits.sort_unstable();
+        hits
+    }
+
+    fn check(intervals: Vec<(u64, u64, FeatureStrand)>, rng: &mut Rng) {
+        let index = IntervalIndex::new(intervals.clone());
+        assert_eq!(index.len(), intervals.len());
+        // 每个区间的两端，以及随机查询和覆盖全部的查询
+        let mut queries: Vec<(u64, u64)> = vec![(0, u64::MAX), (0, 0), (u64::MAX - 1, u64::MAX)];
+        for (s, e, _) in &intervals {
+            queries.extend([(*s, *s + 1), (s.saturating_sub(1), *s), (*s, *s), (e.saturating_sub(1), *e), (*e, *e + 1), (*e, *e), (*s, *e)]);
+        }
+        for _ in 0..200 {
+            let start = rng.below(12000);
+            queries.push((start, start + rng.below(300)));
+        }
+        for (start, end) in queries {
+            let mut hits: Vec<(u64, u64, u8)> = Vec::new();
+            index.for_each_overlap(start, end, |s, e, strand| hits.push((s, e, strand)));
+            hits.sort_unstable();
+            let expected = brute_force(&intervals, start, end);
+            assert_eq!(index.strands(start, end), expected.iter().fold(0, |acc, (_, _, strand)| acc | strand));
+            assert_eq!(hits, expected, "n = {}, query = [{}, {})", intervals.len(), start, end);
+        }
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        let mut sizes: Vec<usize> = vec![0, 1, 2, 3];
+        for k in 2..=9 {
+            sizes.extend([(1 << k) - 1, 1 << k, (1 << k) + 1]);
+        }
+        for n in sizes {
+            check(random_intervals(n, &mut rng), &mut rng);
+            check(nested_intervals(n, &mut rng), &mut rng);
+        }
+    }
+
+    #[test]
+    fn loci_group_overlapping_intervals() {
+        let intervals = vec![
+            (20, 30, FeatureStrand::Reverse),
+            (0, 10, FeatureStrand::Forward),
+            (5, 20, FeatureStrand::Forward), // 和(0, 10)重叠，与(20, 30)相邻但不重叠
+            (25, 25, FeatureStrand::Unknown),
+            (40, 50, FeatureStrand::Forward),
+            (45, 46, FeatureStrand::Reverse),
+        ];
+        assert_eq!(IntervalIndex::new(intervals.clone()).locus(0, 100), None);
+        let index = IntervalIndex::new(intervals).with_loci();
+        assert_eq!(index.locus(0, 1), Some(0));
+        assert_eq!(index.locus(19, 21), Some(0)); // 跨两个locus时取靠前的
+        assert_eq!(index.locus(21, 22), Some(1));
+        assert_eq!(index.locus(30, 40), None);
+        assert_eq!(index.locus(45, 46), Some(2));
+    }
+
+    #[test]
+    fn zero_length_intervals() {
+        let mut rng = Rng(42);
+        let intervals: Vec<(u64, u64, FeatureStrand)> = (0..100).map(|i| (i * 5, i * 5, random_strand(&mut rng))).collect();
+        check(intervals, &mut rng);
+        // 与其他区间相同位置、但长度为0的区间
+        check(vec![(10, 10, FeatureStrand::Forward), (10, 20, FeatureStrand::Reverse), (20, 20, FeatureStrand::Unknown)], &mut rng);
+    }
+}
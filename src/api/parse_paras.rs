@@ -1,32 +1,64 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use argh::FromArgs;
 
 /// error: 定义的错误类型，用于错误传递
 use crate::{
+    annotation::{AnnotationFormat, AnnotationSource, ParseMode, UnstrandedMode},
+    bed::BedSource,
+    cache::{is_cache, CacheSource},
+    contig::AssemblyCheck,
     error::MyError,
+    genepred::{detect_format, GenePredSource},
+    gff3::Gff3Source,
+    gtf::{AttrFilter, GtfSource},
+    infer::Layout,
+    stats::{Bootstrap, BootstrapUnit},
 };
 
 #[derive(FromArgs)]
 #[argh(help_triggers("-h", "--help"))] // https://github.com/google/argh/pull/106
 /// infer experiment
 struct Paras {
+    #[argh(subcommand)]
+    command: Option<SubCommand>,
+
     /// input alignment file in SAM or BAM format
     #[argh(option, short = 'i')]
-    input_file: String,
+    input_file: Option<String>,
 
-    /// reference gene model in bed fomat
+    /// reference gene model in bed, refFlat or genePred fomat, plain or compressed (gzip, bgzip, bzip2, xz, zstd), or an annotation cache built by the index subcommand
     #[argh(option, short = 'r')]
     refgene: Option<String>,
 
-    /// reference gtf file
+    /// format of -r, auto, bed, refflat or genepred (also genePredExt, knownGene and UCSC tables with a bin column). default=auto (detected from the column count)
+    #[argh(option, short = 'a')]
+    annotation_format: Option<String>,
+
+    /// reference gtf file, plain or compressed (gzip, bgzip, bzip2, xz, zstd), or an annotation cache built by the index subcommand
     #[argh(option, short = 'g')]
     gtf: Option<String>,
 
-    /// gtf feature, default: gene
+    /// gtf attribute filter, can be repeated: key=value keeps features whose attribute matches, key!=value excludes them, value can be a comma-separated list or @file (one value per line), e.g. gene_type=protein_coding, tag=basic, gene_id!=@exclude_ids.txt
+    #[argh(option)]
+    gtf_attr: Vec<String>,
+
+    /// reference gff3 file, plain or compressed (gzip, bgzip, bzip2, xz, zstd), or an annotation cache built by the index subcommand
+    #[argh(option)]
+    gff3: Option<String>,
+
+    /// gtf/gff3 feature (for gff3, gene/transcript/exon are selected by the ID/Parent hierarchy; for bed12/refFlat/genePred, exon uses exon blocks and others use the whole transcript; for gtf, merged_exon collapses the exons of each gene into non-overlapping intervals), default: gene
     #[argh(option, short = 'f')]
     feature: Option<String>,
 
+    /// with -f merged_exon, also remove regions lying in an intron of another gene (on either strand)
+    #[argh(switch)]
+    exclude_intronic: bool,
+
+    /// when -r/-g/--gff3 is an annotation cache, recompute the crc32 of the source annotation it was built from and stop if it changed (a changed file size is always reported)
+    #[argh(switch)]
+    verify_cache: bool,
+
     /// number of reads sampled from SAM/BAM file. default=200000
     #[argh(option, short = 's')]
     sample_size: Option<usize>,
@@ -34,58 +66,280 @@ struct Paras {
     /// minimum mapping quality (phred scaled) for an alignment to be considered as \"uniquely mapped\". default=30
     #[argh(option, short = 'q')]
     mapq: Option<u8>,
+
+    /// how to handle features without strand information, skip or separate. default=separate
+    #[argh(option, short = 'u')]
+    unstranded: Option<String>,
+
+    /// which layout drives the verdict (and the --ci-width check) when both PairEnd and SingleEnd reads exist, majority, paired or single. default=majority
+    #[argh(option, short = 'l')]
+    layout: Option<String>,
+
+    /// treat paired reads that are neither first nor last in template as SingleEnd reads, default: skip them
+    #[argh(switch)]
+    unknown_mate_as_single: bool,
+
+    /// how to handle malformed annotation lines, strict (report file, line and reason) or lenient (skip them). default=strict
+    #[argh(option, short = 'p')]
+    parse_mode: Option<String>,
+
+    /// chromosome alias table, a NCBI assembly report, UCSC chromAlias.txt or tsv with equivalent names on each line (e.g. 1<TAB>chr1<TAB>NC_000001.11), used when the BAM and annotation name contigs differently. chr prefix, chrM/MT and human/mouse RefSeq accessions (of the species whose chr1 length matches the BAM header) are matched automatically
+    #[argh(option)]
+    chr_alias: Option<String>,
+
+    /// what to do when the BAM and annotation look like different assemblies (features beyond the @SQ contig lengths, or most features on contigs absent from the BAM header), off, warn or error. default=warn
+    #[argh(option)]
+    assembly_check: Option<String>,
+
+    /// number of threads for BAM/CRAM decompression (htslib thread pool, single-threaded with the noodles backend) and bgzip-compressed annotation. default=1
+    #[argh(option, short = 't')]
+    threads: Option<usize>,
+
+    /// scan all reads instead of stopping after -s usable reads; with -t > 1 and an indexed BAM, contigs are processed in parallel
+    #[argh(switch)]
+    whole_file: bool,
+
+    /// also print the counts of each contig
+    #[argh(switch)]
+    per_contig: bool,
+
+    /// also write the raw counts to this file, e.g. one per region shard, to be combined later with the merge subcommand
+    #[argh(option)]
+    counts_out: Option<String>,
+
+    /// stop sampling before -s once the 95% confidence interval of the dominant strandedness fraction (within the layout chosen by -l) is narrower than this width (e.g. 0.01), checked every 1000 usable reads
+    #[argh(option)]
+    ci_width: Option<f64>,
+
+    /// minimum usable reads before stopping early with --ci-width. default=5000
+    #[argh(option)]
+    min_reads: Option<usize>,
+
+    /// stop scanning after reading this many records (usable or not) and report partial results, useful when few reads overlap the annotation (approximate when contigs are processed in parallel)
+    #[argh(option)]
+    max_records: Option<usize>,
+
+    /// stop scanning after this many seconds and report partial results
+    #[argh(option)]
+    max_seconds: Option<f64>,
+
+    /// number of bootstrap replicates for an extra 95% confidence interval of each reported fraction (the Wilson interval is always printed), resampling reads, genes or contigs with --bootstrap-unit. default=0 (off)
+    #[argh(option)]
+    bootstrap: Option<usize>,
+
+    /// what --bootstrap resamples, reads, genes (groups of overlapping annotation features, accounts for reads from the same gene being correlated) or contigs; genes and contigs need at least 2 of them with usable reads. default=reads
+    #[argh(option)]
+    bootstrap_unit: Option<String>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand)]
+enum SubCommand {
+    Index(IndexArgs),
+    Merge(MergeArgs),
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "index", help_triggers("-h", "--help"))]
+/// parse the annotation once and save the selected intervals to a binary cache, which can be passed to -r, -g or --gff3 directly (-a, -f, --exclude-intronic and --gtf-attr given with a cache must match the ones it was built with)
+struct IndexArgs {
+    /// reference gene model in bed, refFlat or genePred fomat, plain or compressed (gzip, bgzip, bzip2, xz, zstd)
+    #[argh(option, short = 'r')]
+    refgene: Option<String>,
+
+    /// format of -r, auto, bed, refflat or genepred. default=auto
+    #[argh(option, short = 'a')]
+    annotation_format: Option<String>,
+
+    /// reference gtf file, plain or compressed (gzip, bgzip, bzip2, xz, zstd)
+    #[argh(option, short = 'g')]
+    gtf: Option<String>,
+
+    /// gtf attribute filter, can be repeated, same as the main command
+    #[argh(option)]
+    gtf_attr: Vec<String>,
+
+    /// reference gff3 file, plain or compressed (gzip, bgzip, bzip2, xz, zstd)
+    #[argh(option)]
+    gff3: Option<String>,
+
+    /// gtf/gff3 feature, exon also expands exon blocks of bed12/refFlat/genePred, merged_exon collapses the exons of each gtf gene, default: gene
+    #[argh(option, short = 'f')]
+    feature: Option<String>,
+
+    /// with -f merged_exon, also remove regions lying in an intron of another gene (on either strand)
+    #[argh(switch)]
+    exclude_intronic: bool,
+
+    /// how to handle malformed annotation lines, strict or lenient. default=strict
+    #[argh(option, short = 'p')]
+    parse_mode: Option<String>,
+
+    /// output annotation cache file
+    #[argh(option, short = 'o')]
+    output: String,
+
+    /// number of threads for bgzip-compressed annotation decompression. default=1
+    #[argh(option, short = 't')]
+    threads: Option<usize>,
+}
+
+#[derive(FromArgs)]
+#[argh(subcommand, name = "merge", help_triggers("-h", "--help"))]
+/// sum the counts files written by --counts-out (e.g. one per region shard) and print the same report as a single run over all reads
+struct MergeArgs {
+    /// counts files written by --counts-out
+    #[argh(positional)]
+    inputs: Vec<String>,
+
+    /// which layout drives the verdict when both PairEnd and SingleEnd reads exist, majority, paired or single. default=majority
+    #[argh(option, short = 'l')]
+    layout: Option<String>,
+
+    /// also print the counts of each contig
+    #[argh(switch)]
+    per_contig: bool,
+
+    /// also write the merged counts to this file, which can be merged again
+    #[argh(option, short = 'o')]
+    output: Option<String>,
+
+    /// number of bootstrap replicates for an extra 95% confidence interval of each reported fraction, same as the main command
+    #[argh(option)]
+    bootstrap: Option<usize>,
+
+    /// what --bootstrap resamples, reads or contigs (counts files have no per-gene counts). default=reads
+    #[argh(option)]
+    bootstrap_unit: Option<String>,
+}
+
+/// 注释相关的参数，主命令和index子命令共用
+pub struct AnnotationParas {
+    pub refgene:     Option<PathBuf>, // 相应物种基因bed、refFlat、genePred文件，或注释缓存
+    pub annotation_format: AnnotationFormat, // -r的格式，默认auto
+    pub gtf:         Option<PathBuf>, // gtf file，或注释缓存
+    pub gff3:        Option<PathBuf>, // gff3 file，或注释缓存
+    pub feature:     String,          // gtf/gff3/refFlat/genePred feature
+    pub gtf_attr:    Vec<AttrFilter>, // gtf属性过滤条件
+    pub parse_mode:  ParseMode,       // 注释文件错误行的处理方式，默认strict
+    pub exclude_intronic: bool,       // -f merged_exon时去掉位于其他基因内含子中的区域
+    pub given:       Vec<&'static str>, // 命令行中明确指定的-a、-f、--exclude-intronic、--gtf-attr、-p，使用注释缓存时与缓存记录的参数比较
+    pub verify_cache: bool,           // 使用注释缓存时重新计算源注释的crc32，改变则报错
+}
+
+/// 命令行中注释相关的原始参数，主命令和index子命令分别构建
+struct RawAnnotation {
+    refgene:           Option<String>,
+    annotation_format: Option<String>,
+    gtf:               Option<String>,
+    gtf_attr:          Vec<String>,
+    gff3:              Option<String>,
+    feature:           Option<String>,
+    parse_mode:        Option<String>,
+    exclude_intronic:  bool,
+    verify_cache:      bool,
 }
 
 /// 存储解析后的命令行参数
 ///#[derive(Debug, Default)]
 pub struct ParsedParas {
     pub input_file:  PathBuf,         // bam比对文件
-    pub refgene:     Option<PathBuf>, // 相应物种基因bed文件
-    pub gtf:         Option<PathBuf>, // gtf file
-    pub feature:     String,          // gtf feature
+    pub annotation:  AnnotationParas, // 注释相关参数
     pub sample_size: usize,           // 对bam前几个符合筛选条件的record进行统计，默认1000
     pub mapq:        u8,              // mapq阈值，默认30
+    pub unstranded:  UnstrandedMode,  // 无链信息feature的处理方式，默认separate
+    pub layout:      Layout,          // 双端和单端read混合时用哪部分给出结论，默认majority
+    pub unknown_mate_as_single: bool, // 既不是first也不是last的双端read当作单端read统计，默认跳过
+    pub chr_alias:   Option<PathBuf>, // contig别名表
+    pub assembly_check: AssemblyCheck, // bam和注释基因组版本不一致时的处理方式，默认warn
+    pub threads:     usize,           // bam和bgzip注释解压的线程数，默认1
+    pub whole_file:  bool,            // 统计所有read，不受sample_size限制，多线程且有索引时按contig并行
+    pub per_contig:  bool,            // 输出每个contig的统计
+    pub counts_out:  Option<PathBuf>, // 统计结果写入的文件，用于merge子命令合并
+    pub ci_width:    Option<f64>,     // 占多数的链特异性比例的置信区间宽度小于它时提前停止采样
+    pub min_reads:   usize,           // 提前停止前至少统计的usable read数量，默认5000
+    pub max_records: Option<usize>,   // 最多读取的record数量，超出时停止并报告不完整的结果
+    pub max_seconds: Option<f64>,     // 最长扫描时间（秒），超出时停止并报告不完整的结果
+    pub bootstrap:   Option<Bootstrap>, // 比例的bootstrap置信区间，默认不计算
+}
+
+/// index子命令解析后的参数
+pub struct IndexParas {
+    pub annotation: AnnotationParas, // 注释相关参数
+    pub output:     PathBuf,         // 输出的注释缓存
+    pub threads:    usize,           // bgzip注释解压的线程数，默认1
+}
+
+/// merge子命令解析后的参数
+pub struct MergeParas {
+    pub inputs:     Vec<PathBuf>,    // --counts-out输出的统计结果
+    pub layout:     Layout,          // 双端和单端read混合时用哪部分给出结论，默认majority
+    pub per_contig: bool,            // 输出每个contig的统计
+    pub output:     Option<PathBuf>, // 合并后的统计结果写入的文件
+    pub bootstrap:  Option<Bootstrap>, // 比例的bootstrap置信区间，默认不计算
+}
+
+/// 解析后的命令
+pub enum Command {
+    Infer(ParsedParas), // 统计链特异性
+    Index(IndexParas),  // 生成注释缓存
+    Merge(MergeParas),  // 合并多个统计结果
 }
 
 /// 解析参数
-pub fn parse_para() -> Result<ParsedParas, MyError> {
+pub fn parse_para() -> Result<Command, MyError> {
     let para: Paras = argh::from_env();
-    let out: ParsedParas = ParsedParas{
-        input_file: {
-            let tmp_bam = PathBuf::from(&para.input_file);
-            if !(tmp_bam.exists() && tmp_bam.is_file()) {
-                return Err(MyError::FileNotExistError{file: para.input_file})
-            }
-            tmp_bam
-        },
-        refgene: match para.refgene {
-            Some(bed) => {
-                let tmp_bed = PathBuf::from(&bed);
-                if !(tmp_bed.exists() && tmp_bed.is_file()) {
-                    return Err(MyError::FileNotExistError{file: bed})
-                }
-                // -f only valid for -g
-                if para.feature.is_some() {
-                    println!("Warning - -f only valid for -g");
-                }
-                Some(tmp_bed)
+    if let Some(SubCommand::Merge(merge)) = para.command {
+        if merge.inputs.is_empty() {
+            return Err(MyError::ParaError{para: "merge needs at least one counts file".to_string()})
+        }
+        return Ok(Command::Merge(MergeParas{
+            inputs: merge.inputs.into_iter().map(existing_file).collect::<Result<Vec<_>, _>>()?,
+            layout: match merge.layout {
+                Some(l) => l.parse()?,
+                None => Layout::Majority,
             },
-            None => None,
-        },
-        gtf: match para.gtf {
-            Some(g) => {
-                let tmp_gtf = PathBuf::from(&g);
-                if !(tmp_gtf.exists() && tmp_gtf.is_file()) {
-                    return Err(MyError::FileNotExistError{file: g})
-                }
-                Some(tmp_gtf)
+            per_contig: merge.per_contig,
+            output: merge.output.map(PathBuf::from),
+            bootstrap: match parse_bootstrap(merge.bootstrap, merge.bootstrap_unit)? {
+                Some(b) if b.unit == BootstrapUnit::Genes => return Err(MyError::ParaError{para: "--bootstrap-unit genes couldn't be used with merge, counts files only store per-contig counts".to_string()}),
+                b => b,
             },
-            None => None,
-        },
-        feature: match para.feature {
-            Some(f) => f,
-            None => "gene".to_string(),
+        }))
+    }
+    if let Some(SubCommand::Index(index)) = para.command {
+        return Ok(Command::Index(IndexParas{
+            annotation: parse_annotation(RawAnnotation{
+                refgene: index.refgene,
+                annotation_format: index.annotation_format,
+                gtf: index.gtf,
+                gtf_attr: index.gtf_attr,
+                gff3: index.gff3,
+                feature: index.feature,
+                parse_mode: index.parse_mode,
+                exclude_intronic: index.exclude_intronic,
+                verify_cache: false,
+            })?,
+            output: PathBuf::from(index.output),
+            threads: parse_threads(index.threads)?,
+        }))
+    }
+    let out: ParsedParas = ParsedParas{
+        input_file: match para.input_file {
+            Some(i) => existing_file(i)?,
+            None => return Err(MyError::ParaError{para: "you must specify -i".to_string()}),
         },
+        annotation: parse_annotation(RawAnnotation{
+            refgene: para.refgene,
+            annotation_format: para.annotation_format,
+            gtf: para.gtf,
+            gtf_attr: para.gtf_attr,
+            gff3: para.gff3,
+            feature: para.feature,
+            parse_mode: para.parse_mode,
+            exclude_intronic: para.exclude_intronic,
+            verify_cache: para.verify_cache,
+        })?,
         sample_size: match para.sample_size {
             Some(n) => {
                 if n < 1000 {
@@ -95,18 +349,224 @@ pub fn parse_para() -> Result<ParsedParas, MyError> {
             },
             None => 200000,
         },
-        mapq: match para.mapq {
-            Some(m) => m,
-            None => 30,
+        mapq: para.mapq.unwrap_or(30),
+        unstranded: match para.unstranded {
+            Some(u) => u.parse()?,
+            None => UnstrandedMode::Separate,
+        },
+        layout: match para.layout {
+            Some(l) => l.parse()?,
+            None => Layout::Majority,
+        },
+        unknown_mate_as_single: para.unknown_mate_as_single,
+        chr_alias: para.chr_alias.map(existing_file).transpose()?,
+        assembly_check: match para.assembly_check {
+            Some(a) => a.parse()?,
+            None => AssemblyCheck::Warn,
         },
+        threads: parse_threads(para.threads)?,
+        whole_file: para.whole_file,
+        per_contig: para.per_contig,
+        counts_out: para.counts_out.map(PathBuf::from),
+        ci_width: match para.ci_width {
+            Some(w) if !(w > 0.0 && w < 1.0) => return Err(MyError::ParaError{para: format!("--ci-width should be between 0 and 1, not {}", w)}),
+            Some(_) if para.whole_file => return Err(MyError::ParaError{para: "--ci-width couldn't be used with --whole-file".to_string()}),
+            w => w,
+        },
+        min_reads: para.min_reads.unwrap_or(5000),
+        max_records: para.max_records,
+        max_seconds: match para.max_seconds {
+            Some(s) if !(s > 0.0 && s.is_finite()) => return Err(MyError::ParaError{para: format!("--max-seconds should be greater than 0, not {}", s)}),
+            s => s,
+        },
+        bootstrap: parse_bootstrap(para.bootstrap, para.bootstrap_unit)?,
+    };
+    if out.ci_width.is_none() && para.min_reads.is_some() {
+        println!("Warning - --min-reads only valid for --ci-width");
+    }
+
+    Ok(Command::Infer(out))
+}
+
+/// 解析bootstrap参数，重抽样次数为0时不计算
+fn parse_bootstrap(replicates: Option<usize>, unit: Option<String>) -> Result<Option<Bootstrap>, MyError> {
+    let unit_set = unit.is_some();
+    let unit = match unit {
+        Some(u) => u.parse()?,
+        None => BootstrapUnit::Reads,
     };
-    // must specify -r or -g, but cannot use -r and -g simultaneously
-    match (out.refgene.is_some(), out.gtf.is_some()) {
-        (true, true) => return Err(MyError::ParaError{para: "couldn't specify -r and -g simultaneously".to_string()}),
-        (true, false) => (),
-        (false, true) => (),
-        (false, false) => return Err(MyError::ParaError{para: "you must specify -r or -g".to_string()}),
+    match replicates {
+        Some(replicates) if replicates > 0 => Ok(Some(Bootstrap{replicates, unit})),
+        _ => {
+            if unit_set {
+                println!("Warning - --bootstrap-unit only valid for --bootstrap");
+            }
+            Ok(None)
+        },
+    }
+}
+
+/// 检查线程数，默认1
+fn parse_threads(threads: Option<usize>) -> Result<usize, MyError> {
+    match threads {
+        Some(0) => Err(MyError::ParaError{para: "-t should be at least 1".to_string()}),
+        Some(t) => Ok(t),
+        None => Ok(1),
+    }
+}
+
+/// 检查文件是否存在
+fn existing_file(file: String) -> Result<PathBuf, MyError> {
+    let tmp_file = PathBuf::from(&file);
+    if !(tmp_file.exists() && tmp_file.is_file()) {
+        return Err(MyError::FileNotExistError{file})
     }
+    Ok(tmp_file)
+}
 
+/// 解析注释相关参数
+fn parse_annotation(raw: RawAnnotation) -> Result<AnnotationParas, MyError> {
+    let given: Vec<&'static str> = [
+        ("-a", raw.annotation_format.is_some()),
+        ("-f", raw.feature.is_some()),
+        ("--exclude-intronic", raw.exclude_intronic),
+        ("--gtf-attr", !raw.gtf_attr.is_empty()),
+        ("-p", raw.parse_mode.is_some()),
+    ].into_iter().filter(|(_, g)| *g).map(|(o, _)| o).collect();
+    let out = AnnotationParas{
+        refgene: raw.refgene.map(existing_file).transpose()?,
+        annotation_format: match raw.annotation_format {
+            Some(a) => a.parse()?,
+            None => AnnotationFormat::Auto,
+        },
+        gtf: raw.gtf.map(existing_file).transpose()?,
+        gff3: raw.gff3.map(existing_file).transpose()?,
+        gtf_attr: raw.gtf_attr.iter().map(|f| AttrFilter::new(f)).collect::<Result<Vec<_>, _>>()?,
+        feature: match raw.feature {
+            Some(f) => f,
+            None => "gene".to_string(),
+        },
+        parse_mode: match raw.parse_mode {
+            Some(p) => p.parse()?,
+            None => ParseMode::Strict,
+        },
+        exclude_intronic: raw.exclude_intronic,
+        given,
+        verify_cache: raw.verify_cache,
+    };
+    // --gtf-attr only valid for -g
+    if !out.gtf_attr.is_empty() && out.gtf.is_none() {
+        println!("Warning - --gtf-attr only valid for -g");
+    }
+    // must specify one of -r, -g and --gff3
+    match [out.refgene.is_some(), out.gtf.is_some(), out.gff3.is_some()].iter().filter(|s| **s).count() {
+        0 => return Err(MyError::ParaError{para: "you must specify -r, -g or --gff3".to_string()}),
+        1 => (),
+        _ => return Err(MyError::ParaError{para: "couldn't specify -r, -g and --gff3 simultaneously".to_string()}),
+    }
+    // -f merged_exon only valid for -g
+    if out.feature == "merged_exon" && out.gtf.is_none() {
+        return Err(MyError::ParaError{para: "-f merged_exon only valid for -g".to_string()})
+    }
+    if out.exclude_intronic && out.feature != "merged_exon" {
+        println!("Warning - --exclude-intronic only valid for -f merged_exon");
+    }
     Ok(out)
 }
+
+impl AnnotationParas {
+    /// -r、-g、--gff3中指定的注释文件
+    pub fn path(&self) -> Result<&Path, MyError> {
+        match (&self.refgene, &self.gtf, &self.gff3) {
+            (Some(p), None, None) | (None, Some(p), None) | (None, None, Some(p)) => Ok(p),
+            _ => Err(MyError::ParaError{para: "you must specify only one of -r, -g and --gff3".to_string()}),
+        }
+    }
+
+    /// 是否使用基因模型的exon结构
+    pub fn use_exons(&self) -> bool {
+        self.feature == "exon" || self.feature == "merged_exon"
+    }
+
+    /// -r的实际格式，auto时根据内容判断
+    fn refgene_format(&self, file: &Path) -> Result<AnnotationFormat, MyError> {
+        match self.annotation_format {
+            AnnotationFormat::Auto => detect_format(file),
+            f => Ok(f),
+        }
+    }
+
+    /// 注释格式，-r为auto时根据内容判断
+    pub fn format_name(&self) -> Result<&'static str, MyError> {
+        Ok(match (&self.refgene, &self.gtf) {
+            (Some(r), _) => match self.refgene_format(r)? {
+                AnnotationFormat::RefFlat => "refflat",
+                AnnotationFormat::GenePred => "genepred",
+                _ => "bed",
+            },
+            (None, Some(_)) => "gtf",
+            _ => "gff3",
+        })
+    }
+
+    /// 注释格式、feature、过滤条件和解析方式的描述，写入注释缓存用于追溯
+    pub fn description(&self) -> Result<String, MyError> {
+        let filters: Vec<String> = self.gtf_attr.iter().map(|f| f.to_string()).collect();
+        Ok(format!(
+            "format: {}, feature: {}{}, gtf-attr: {}, parse-mode: {}",
+            self.format_name()?,
+            self.feature,
+            if self.exclude_intronic && self.feature == "merged_exon" { " (excluding introns of other genes)" } else { "" },
+            if filters.is_empty() { "none".to_string() } else { filters.join(" ") },
+            match self.parse_mode {
+                ParseMode::Strict => "strict",
+                ParseMode::Lenient => "lenient",
+            },
+        ))
+    }
+
+    /// 写入注释缓存、之后使用缓存时需要一致的参数，(参数名, 值)，顺序固定
+    pub fn cache_options(&self, format: &str) -> Vec<(&'static str, String)> {
+        let filters: Vec<String> = self.gtf_attr.iter().map(|f| f.to_string()).collect();
+        vec![
+            ("-a", format.to_string()),
+            ("-f", self.feature.clone()),
+            ("--exclude-intronic", (self.exclude_intronic && self.feature == "merged_exon").to_string()),
+            ("--gtf-attr", filters.join(" ")),
+            ("-p", match self.parse_mode {
+                ParseMode::Strict => "strict".to_string(),
+                ParseMode::Lenient => "lenient".to_string(),
+            }),
+        ]
+    }
+
+    /// 根据-r、-g、--gff3得到注释来源，threads为bgzip压缩的注释的解压线程数
+    /// 注释缓存直接读取，命令行中明确指定的格式、feature、过滤条件和解析方式需要与建立缓存时一致（-a auto不比较）
+    pub fn annotation_source(&self, threads: usize) -> Result<Box<dyn AnnotationSource>, MyError> {
+        let path = self.path()?;
+        if is_cache(path)? {
+            let format = match self.annotation_format {
+                AnnotationFormat::Auto => "auto",
+                AnnotationFormat::Bed => "bed",
+                AnnotationFormat::RefFlat => "refflat",
+                AnnotationFormat::GenePred => "genepred",
+            };
+            let expected = self.cache_options(format).into_iter()
+                .filter(|(o, v)| self.given.contains(o) && !(*o == "-a" && v == "auto"))
+                .collect();
+            return Ok(Box::new(CacheSource{path: path.to_path_buf(), expected, verify_source: self.verify_cache}))
+        }
+        match (&self.refgene, &self.gtf, &self.gff3) {
+            (Some(r), None, None) => {
+                let format = self.refgene_format(r)?;
+                match format {
+                    AnnotationFormat::Bed => Ok(Box::new(BedSource{path: r.clone(), parse_mode: self.parse_mode, threads})),
+                    _ => Ok(Box::new(GenePredSource{path: r.clone(), format, parse_mode: self.parse_mode, threads})),
+                }
+            },
+            (None, Some(g), None) => Ok(Box::new(GtfSource{path: g.clone(), feature: self.feature.clone(), attr_filters: self.gtf_attr.clone(), parse_mode: self.parse_mode, exclude_intronic: self.exclude_intronic, threads})),
+            (None, None, Some(g)) => Ok(Box::new(Gff3Source{path: g.clone(), feature: self.feature.clone(), parse_mode: self.parse_mode, threads})),
+            _ => Err(MyError::ParaError{para: "you must specify only one of -r, -g and --gff3".to_string()}),
+        }
+    }
+}
@@ -0,0 +1,317 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::error::MyError;
+
+/// 95%置信区间对应的正态分布分位数
+pub const Z_95: f64 = 1.959963984540054;
+
+/// 比例的Wilson score置信区间，n为0时返回(0, 1)
+/// 比例接近0或1、样本量较小时比正态近似更准确
+/// 参考：https://en.wikipedia.org/wiki/Binomial_proportion_confidence_interval#Wilson_score_interval
+pub fn wilson_interval(successes: u64, n: u64, z: f64) -> (f64, f64) {
+    if n == 0 {
+        return (0.0, 1.0)
+    }
+    let n = n as f64;
+    let p = successes as f64 / n;
+    let z2 = z * z;
+    let denominator = 1.0 + z2 / n;
+    let center = (p + z2 / (2.0 * n)) / denominator;
+    let half = z / denominator * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((center - half).max(0.0), (center + half).min(1.0))
+}
+
+/// bootstrap重抽样的单位
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootstrapUnit {
+    Reads,   // 对usable read有放回抽样
+    Genes,   // 对有usable read的基因（相互重叠的feature组成的locus）有放回抽样，考虑同一基因上read的相关性
+    Contigs, // 对有usable read的contig有放回抽样，可以用于merge子命令合并的统计
+}
+
+impl FromStr for BootstrapUnit {
+    type Err = MyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reads" => Ok(BootstrapUnit::Reads),
+            "genes" => Ok(BootstrapUnit::Genes),
+            "contigs" => Ok(BootstrapUnit::Contigs),
+            _ => Err(MyError::ParaError{para: format!("--bootstrap-unit only support reads, genes or contigs, not {}", s)}),
+        }
+    }
+}
+
+impl fmt::Display for BootstrapUnit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BootstrapUnit::Reads => write!(f, "reads"),
+            BootstrapUnit::Genes => write!(f, "genes"),
+            BootstrapUnit::Contigs => write!(f, "contigs"),
+        }
+    }
+}
+
+/// bootstrap参数
+#[derive(Debug, Clone, Copy)]
+pub struct Bootstrap {
+    pub replicates: usize,         // 重抽样次数
+    pub unit:       BootstrapUnit, // 重抽样的单位
+}
+
+/// 固定随机数种子，相同输入的结果可以重复
+const BOOTSTRAP_SEED: u64 = 0x5EED_1F0E_C7ED_0001;
+
+/// splitmix64随机数生成器，只用于bootstrap抽样，不需要额外依赖
+/// 参考：https://prng.di.unimi.it/splitmix64.c
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// [0, n)之间的随机数，n远小于2^64时偏差可以忽略
+    fn below(&mut self, n: u64) -> u64 {
+        ((self.next_u64() as u128 * n as u128) >> 64) as u64
+    }
+
+    /// [0, 1)之间均匀分布的随机数
+    fn uniform(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// 二项分布B(n, p)的随机数，耗时与n无关
+    /// n * p较小时用几何分布的等待时间逐个计数，否则用Hörmann的BTRS（transformed rejection with squeeze）
+    /// 参考：W. Hörmann, The generation of binomial random variates, 1993；PyTorch的binomial实现
+    fn binomial(&mut self, n: u64, p: f64) -> u64 {
+        if n == 0 || p <= 0.0 {
+            return 0
+        }
+        if p >= 1.0 {
+            return n
+        }
+        if p > 0.5 {
+            return n - self.binomial(n, 1.0 - p)
+        }
+        if n as f64 * p < 10.0 {
+            // 两次成功之间的试验次数服从几何分布，累计超过n时停止
+            let log_q = (-p).ln_1p();
+            let mut trials = 0.0;
+            let mut k = 0;
+            loop {
+                trials += (self.uniform().ln() / log_q).ceil();
+                if trials > n as f64 {
+                    return k
+                }
+                k += 1;
+            }
+        }
+        let count = n as f64;
+        let stddev = (count * p * (1.0 - p)).sqrt();
+        let b = 1.15 + 2.53 * stddev;
+        let a = -0.0873 + 0.0248 * b + 0.01 * p;
+        let c = count * p + 0.5;
+        let v_r = 0.92 - 4.2 / b;
+        let r = p / (1.0 - p);
+        let alpha = (2.83 + 5.1 / b) * stddev;
+        let m = ((count + 1.0) * p).floor();
+        loop {
+            let u = self.uniform() - 0.5;
+            let v = self.uniform();
+            let us = 0.5 - u.abs();
+            let k = ((2.0 * a / us + b) * u + c).floor();
+            if k < 0.0 || k > count {
+                continue
+            }
+            if us >= 0.07 && v <= v_r {
+                return k as u64
+            }
+            let v = (v * alpha / (a / (us * us) + b)).ln();
+            let bound = (m + 0.5) * ((m + 1.0) / (r * (count - m + 1.0))).ln()
+                + (count + 1.0) * ((count - m + 1.0) / (count - k + 1.0)).ln()
+                + (k + 0.5) * (r * (count - k + 1.0) / (k + 1.0)).ln()
+                + stirling_tail(m) + stirling_tail(count - m) - stirling_tail(k) - stirling_tail(count - k);
+            if v <= bound {
+                return k as u64
+            }
+        }
+    }
+
+    /// 多项分布的随机数，n次试验，每一类的概率与weights成正比，依次用条件二项分布抽取每一类的数量
+    fn multinomial<const K: usize>(&mut self, n: u64, weights: &[u64; K]) -> [u64; K] {
+        let mut counts = [0u64; K];
+        let mut remaining = n;
+        let mut rest: u64 = weights.iter().sum();
+        for (c, &w) in counts.iter_mut().zip(weights) {
+            if remaining == 0 || rest == 0 {
+                break
+            }
+            *c = if w == rest { remaining } else { self.binomial(remaining, w as f64 / rest as f64) };
+            remaining -= *c;
+            rest -= w;
+        }
+        counts
+    }
+}
+
+/// ln(k!)的Stirling近似的余项，k较小时查表
+fn stirling_tail(k: f64) -> f64 {
+    const TAIL: [f64; 10] = [
+        0.0810614667953272, 0.0413406959554092, 0.0276779256849983, 0.02079067210376509, 0.0166446911898211,
+        0.0138761288230707, 0.0118967099458917, 0.0104112652619720, 0.00925546218271273, 0.00833056343336287,
+    ];
+    if k <= 9.0 {
+        return TAIL[k as usize]
+    }
+    let kp1_sq = (k + 1.0) * (k + 1.0);
+    (1.0 / 12.0 - (1.0 / 360.0 - 1.0 / 1260.0 / kp1_sq) / kp1_sq) / (k + 1.0)
+}
+
+/// 每一类比例的bootstrap 95%百分位数置信区间
+/// units为每个重抽样单位（基因或contig）中各类read的数量
+/// 按read抽样时先合并所有单位，有放回抽取和原始数据相同数量的read，各类的数量服从多项分布，每次重抽样直接抽取多项分布，耗时与read数量无关
+/// 没有可以抽样的read，或按基因、contig抽样时少于2个单位，返回None
+pub fn bootstrap_intervals<const K: usize>(units: &[[u64; K]], bootstrap: &Bootstrap) -> Option<[(f64, f64); K]> {
+    let mut rng = SplitMix64(BOOTSTRAP_SEED);
+    let mut fractions: Vec<Vec<f64>> = vec![Vec::with_capacity(bootstrap.replicates); K];
+    match bootstrap.unit {
+        BootstrapUnit::Reads => {
+            let mut total = [0u64; K];
+            for unit in units {
+                for (t, c) in total.iter_mut().zip(unit) {
+                    *t += c;
+                }
+            }
+            let n: u64 = total.iter().sum();
+            if n == 0 {
+                return None
+            }
+            for _ in 0..bootstrap.replicates {
+                let counts = rng.multinomial(n, &total);
+                for (f, c) in fractions.iter_mut().zip(&counts) {
+                    f.push(*c as f64 / n as f64);
+                }
+            }
+        },
+        BootstrapUnit::Genes | BootstrapUnit::Contigs => {
+            let units: Vec<&[u64; K]> = units.iter().filter(|u| u.iter().sum::<u64>() > 0).collect();
+            if units.len() < 2 {
+                return None
+            }
+            for _ in 0..bootstrap.replicates {
+                let mut counts = [0u64; K];
+                for _ in 0..units.len() {
+                    for (c, u) in counts.iter_mut().zip(units[rng.below(units.len() as u64) as usize]) {
+                        *c += u;
+                    }
+                }
+                let n: u64 = counts.iter().sum();
+                for (f, c) in fractions.iter_mut().zip(&counts) {
+                    f.push(*c as f64 / n as f64);
+                }
+            }
+        },
+    }
+    let mut intervals = [(0.0, 1.0); K];
+    for (interval, f) in intervals.iter_mut().zip(fractions.iter_mut()) {
+        f.sort_unstable_by(|a, b| a.total_cmp(b));
+        *interval = (quantile(f, 0.025), quantile(f, 0.975));
+    }
+    Some(intervals)
+}
+
+/// 排好序的数据的分位数，相邻两个值之间线性插值
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN
+    }
+    let pos = q * (sorted.len() - 1) as f64;
+    let (lower, upper) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (pos - lower as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: (f64, f64), b: (f64, f64), tolerance: f64) {
+        assert!((a.0 - b.0).abs() < tolerance && (a.1 - b.1).abs() < tolerance, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn wilson_known_values() {
+        // R: prop.test(x, n, correct = FALSE)$conf.int
+        assert_close(wilson_interval(5, 10, Z_95), (0.2365931, 0.7634069), 1e-7);
+        assert_close(wilson_interval(0, 10, Z_95), (0.0, 0.2775328), 1e-7);
+        assert_close(wilson_interval(10, 10, Z_95), (0.7224672, 1.0), 1e-7);
+        assert_close(wilson_interval(81, 263, Z_95), (0.2552885, 0.3662096), 1e-7);
+        assert_eq!(wilson_interval(0, 0, Z_95), (0.0, 1.0));
+    }
+
+    #[test]
+    fn quantile_interpolates() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(quantile(&sorted, 0.0), 1.0);
+        assert_eq!(quantile(&sorted, 1.0), 4.0);
+        assert_eq!(quantile(&sorted, 0.5), 2.5);
+        assert_eq!(quantile(&sorted, 0.25), 1.75);
+        assert_eq!(quantile(&[7.0], 0.975), 7.0);
+        assert!(quantile(&[], 0.5).is_nan());
+    }
+
+    #[test]
+    fn binomial_moments() {
+        let mut rng = SplitMix64(BOOTSTRAP_SEED);
+        // 分别走几何分布计数、BTRS，以及p > 0.5时取反
+        for (n, p) in [(20, 0.1), (1000, 0.3), (1_000_000, 0.6), (7, 0.5)] {
+            let draws = 20000;
+            let samples: Vec<u64> = (0..draws).map(|_| rng.binomial(n, p)).collect();
+            assert!(samples.iter().all(|&k| k <= n));
+            let mean = samples.iter().sum::<u64>() as f64 / draws as f64;
+            let var = samples.iter().map(|&k| (k as f64 - mean).powi(2)).sum::<f64>() / (draws - 1) as f64;
+            let expected_var = n as f64 * p * (1.0 - p);
+            assert!((mean - n as f64 * p).abs() < 5.0 * (expected_var / draws as f64).sqrt(), "n = {}, p = {}, mean = {}", n, p, mean);
+            assert!((var / expected_var - 1.0).abs() < 0.1, "n = {}, p = {}, var = {}", n, p, var);
+        }
+        assert_eq!(rng.binomial(50, 0.0), 0);
+        assert_eq!(rng.binomial(50, 1.0), 50);
+        assert_eq!(rng.binomial(0, 0.5), 0);
+    }
+
+    #[test]
+    fn multinomial_keeps_total() {
+        let mut rng = SplitMix64(BOOTSTRAP_SEED);
+        for _ in 0..100 {
+            let counts = rng.multinomial(12345, &[10, 0, 30, 60]);
+            assert_eq!(counts.iter().sum::<u64>(), 12345);
+            assert_eq!(counts[1], 0);
+        }
+        assert_eq!(rng.multinomial(0, &[1, 2]), [0, 0]);
+    }
+
+    #[test]
+    fn bootstrap_over_reads_matches_wilson() {
+        let bootstrap = Bootstrap{replicates: 2000, unit: BootstrapUnit::Reads};
+        let units = [[3000, 1500, 250, 250], [3000, 1500, 250, 250]];
+        let intervals = bootstrap_intervals(&units, &bootstrap).unwrap();
+        assert_close(intervals[0], wilson_interval(6000, 10000, Z_95), 0.003);
+        assert_close(intervals[3], wilson_interval(500, 10000, Z_95), 0.003);
+        // 相同输入的结果相同
+        assert_eq!(bootstrap_intervals(&units, &bootstrap), Some(intervals));
+    }
+
+    #[test]
+    fn bootstrap_needs_two_units() {
+        let genes = Bootstrap{replicates: 100, unit: BootstrapUnit::Genes};
+        assert_eq!(bootstrap_intervals(&[[10, 0], [0, 0]], &genes), None);
+        assert!(bootstrap_intervals(&[[10, 0], [3, 5]], &genes).is_some());
+        let reads = Bootstrap{replicates: 100, unit: BootstrapUnit::Reads};
+        assert_eq!(bootstrap_intervals(&[[0u64, 0]], &reads), None);
+    }
+}
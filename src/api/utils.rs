@@ -1,21 +1,337 @@
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Read};
 use std::path::Path;
+use std::thread;
 
-use flate2::read::GzDecoder;
+#[cfg(feature = "bzip2")]
+use bzip2::read::MultiBzDecoder;
+use flate2::read::{DeflateDecoder, MultiGzDecoder};
+use ruzstd::decoding::{
+    errors::{FrameDecoderError, ReadFrameHeaderError},
+    BlockDecodingStrategy, FrameDecoder,
+};
+#[cfg(feature = "xz")]
+use xz2::read::XzDecoder;
 
 use crate::error::MyError;
 
-/// 读取bed或bed.gz，这样可以返回bed或bed.gz的reader
+/// 注释文件的压缩格式，根据文件开头的magic bytes判断，不依赖文件后缀
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    Plain, // 未压缩
+    Gzip,  // gzip
+    Bgzf,  // bgzip，也是gzip格式，extra field中含有"BC"
+    Bzip2, // bzip2
+    Xz,    // xz
+    Zstd,  // zstd
+}
+
+impl Compression {
+    /// 根据magic bytes判断压缩格式
+    /// gzip: 1f 8b, bgzf: 1f 8b 08 04 ... "BC", bzip2: "BZh", xz: fd 37 7a 58 5a 00, zstd: 28 b5 2f fd
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            // bgzf的FLG含有FEXTRA(0x04)，extra subfield标识为"BC"，位于第12、13字节
+            if magic.len() >= 14 && magic[3] & 0x04 != 0 && &magic[12..14] == b"BC" {
+                Compression::Bgzf
+            } else {
+                Compression::Gzip
+            }
+        } else if magic.starts_with(b"BZh") {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00]) {
+            Compression::Xz
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else {
+            Compression::Plain
+        }
+    }
+}
+
+/// 读取未压缩或压缩的注释文件，根据文件内容判断压缩格式，这样可以返回统一的reader
+/// 支持plain、gzip、bgzip、bzip2、xz、zstd，gzip和bgzip都使用MultiGzDecoder，因为bgzip由多个gzip block组成，zstd使用MultiZstdDecoder解压所有frame
+/// threads大于1时，bgzip使用BgzfReader多线程解压，其他格式只能单线程解压
+/// bzip2、xz需要启用同名feature（默认启用），没有启用时报错
 /// 参考：https://users.rust-lang.org/t/write-to-normal-or-gzip-file-transparently/35561/2
 /// 参考：https://github.com/rust-lang/flate2-rs/issues/393
-pub fn my_reader(file: &Path) -> Result<Box<dyn BufRead>, MyError> {
-    let opened_file = File::open(&file).map_err(|e| MyError::ReadFileError{file: file.to_str().unwrap().to_string(), error: e})?;
-    if file.extension().unwrap() == "gz" {
-        //Box::new(BufReader::with_capacity(8 * 1024, GzDecoder::new(opened_file)))
-        Ok(Box::new(BufReader::new(GzDecoder::new(opened_file))))
-    } else {
-        //Box::new(BufReader::with_capacity(8 * 1024, opened_file))
-        Ok(Box::new(BufReader::new(opened_file)))
+pub fn my_reader(file: &Path, threads: usize) -> Result<Box<dyn BufRead>, MyError> {
+    let opened_file = File::open(file).map_err(|e| MyError::ReadFileError{file: file.display().to_string(), error: e})?;
+    let mut buf_reader = BufReader::new(opened_file);
+    // 只查看开头的字节，不消耗，后面解压时仍然从头读取
+    let magic = buf_reader.fill_buf().map_err(|e| MyError::ReadFileError{file: file.display().to_string(), error: e})?;
+    Ok(match Compression::detect(magic) {
+        Compression::Plain => Box::new(buf_reader),
+        Compression::Bgzf if threads > 1 => Box::new(BufReader::new(BgzfReader::new(buf_reader, threads))),
+        Compression::Gzip | Compression::Bgzf => Box::new(BufReader::new(MultiGzDecoder::new(buf_reader))),
+        #[cfg(feature = "bzip2")]
+        Compression::Bzip2 => Box::new(BufReader::new(MultiBzDecoder::new(buf_reader))),
+        #[cfg(not(feature = "bzip2"))]
+        Compression::Bzip2 => return Err(MyError::UnsupportedCompressionError{file: file.display().to_string(), format: "bzip2".to_string()}),
+        #[cfg(feature = "xz")]
+        Compression::Xz => Box::new(BufReader::new(XzDecoder::new_multi_decoder(buf_reader))),
+        #[cfg(not(feature = "xz"))]
+        Compression::Xz => return Err(MyError::UnsupportedCompressionError{file: file.display().to_string(), format: "xz".to_string()}),
+        Compression::Zstd => {
+            let decoder = MultiZstdDecoder::new(buf_reader).map_err(|e| MyError::ReadFileError{file: file.display().to_string(), error: e})?;
+            Box::new(BufReader::new(decoder))
+        },
+    })
+}
+
+/// 依次解压zstd文件中的所有frame，比如pzstd的输出或直接拼接的多个zstd文件，跳过skippable frame
+/// ruzstd的StreamingDecoder只解压第一个frame，后面的数据会被忽略
+struct MultiZstdDecoder<R: BufRead> {
+    source: R,
+    frame:  FrameDecoder,
+}
+
+impl<R: BufRead> MultiZstdDecoder<R> {
+    fn new(source: R) -> io::Result<Self> {
+        let mut decoder = MultiZstdDecoder{source, frame: FrameDecoder::new()};
+        decoder.next_frame()?;
+        Ok(decoder)
+    }
+
+    /// 读取下一个frame的header，没有更多数据时返回false
+    fn next_frame(&mut self) -> io::Result<bool> {
+        loop {
+            if self.source.fill_buf()?.is_empty() {
+                return Ok(false)
+            }
+            match self.frame.reset(&mut self.source) {
+                Ok(()) => return Ok(true),
+                // skippable frame的header已经读取，跳过其内容
+                Err(FrameDecoderError::ReadFrameHeaderError(ReadFrameHeaderError::SkipFrame{length, ..})) => {
+                    io::copy(&mut (&mut self.source).take(length as u64), &mut io::sink())?;
+                },
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidData, e.to_string())),
+            }
+        }
+    }
+}
+
+impl<R: BufRead> Read for MultiZstdDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0)
+        }
+        loop {
+            // 当前frame已解压完且数据都已输出，开始下一个frame
+            if self.frame.is_finished() && self.frame.can_collect() == 0 && !self.next_frame()? {
+                return Ok(0)
+            }
+            while self.frame.can_collect() < buf.len() && !self.frame.is_finished() {
+                let needed = buf.len() - self.frame.can_collect();
+                self.frame.decode_blocks(&mut self.source, BlockDecodingStrategy::UptoBytes(needed)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            }
+            let n = self.frame.read(buf)?;
+            if n > 0 {
+                return Ok(n)
+            }
+        }
+    }
+}
+
+/// 多线程解压bgzip文件，bgzip的每个block都是独立的gzip，且header中记录了block大小（BSIZE）
+/// 每次读取threads * 16个block，分给threads个线程同时解压，再按顺序输出
+/// 参考：https://samtools.github.io/hts-specs/SAMv1.pdf 4.1节
+struct BgzfReader<R: Read> {
+    inner:   R,
+    threads: usize,
+    buf:     Vec<u8>, // 已解压、还没输出的数据
+    pos:     usize,
+    eof:     bool,
+}
+
+impl<R: Read> BgzfReader<R> {
+    fn new(inner: R, threads: usize) -> Self {
+        BgzfReader{inner, threads, buf: Vec::new(), pos: 0, eof: false}
+    }
+
+    /// 读取并解压下一批block
+    fn fill(&mut self) -> io::Result<()> {
+        let mut blocks: Vec<Vec<u8>> = Vec::with_capacity(self.threads * 16);
+        while blocks.len() < self.threads * 16 {
+            match read_bgzf_block(&mut self.inner)? {
+                Some(b) => blocks.push(b),
+                None => {
+                    self.eof = true;
+                    break
+                },
+            }
+        }
+        let chunk_size = blocks.len().div_ceil(self.threads).max(1);
+        let decompressed: Vec<io::Result<Vec<u8>>> = thread::scope(|s| {
+            let handles: Vec<_> = blocks.chunks(chunk_size).map(|chunk| s.spawn(move || {
+                let mut out: Vec<u8> = Vec::new();
+                for block in chunk {
+                    inflate_bgzf_block(block, &mut out)?;
+                }
+                Ok(out)
+            })).collect();
+            handles.into_iter().map(|h| h.join().unwrap_or_else(|_| Err(io::Error::other("bgzf decompression thread panicked")))).collect()
+        });
+        self.buf.clear();
+        self.pos = 0;
+        for out in decompressed {
+            self.buf.extend_from_slice(&out?);
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for BgzfReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // 空block（比如文件末尾的EOF block）解压后没有数据，继续读取下一批
+        while self.pos >= self.buf.len() {
+            if self.eof {
+                return Ok(0)
+            }
+            self.fill()?;
+        }
+        let n = buf.len().min(self.buf.len() - self.pos);
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// 读取一个完整的bgzf block（包括header和trailer），文件结束时返回None
+fn read_bgzf_block<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0u8; 12];
+    let mut n = 0;
+    while n < header.len() {
+        match reader.read(&mut header[n..])? {
+            0 if n == 0 => return Ok(None),
+            0 => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated bgzf block header")),
+            m => n += m,
+        }
+    }
+    if header[..4] != [0x1f, 0x8b, 0x08, 0x04] {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a bgzf block"))
+    }
+    let xlen = u16::from_le_bytes([header[10], header[11]]) as usize;
+    let mut extra = vec![0u8; xlen];
+    reader.read_exact(&mut extra)?;
+    // 在extra subfield中找到BC，得到BSIZE（block总大小减1）
+    let mut bsize: Option<usize> = None;
+    let mut i = 0;
+    while i + 4 <= xlen {
+        let slen = u16::from_le_bytes([extra[i + 2], extra[i + 3]]) as usize;
+        if &extra[i..i + 2] == b"BC" && slen == 2 && i + 6 <= xlen {
+            bsize = Some(u16::from_le_bytes([extra[i + 4], extra[i + 5]]) as usize);
+        }
+        i += 4 + slen;
+    }
+    let bsize = bsize.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bgzf block without BC field"))?;
+    // 至少包含header、extra和8字节的trailer（CRC32、ISIZE）
+    if bsize + 1 < 12 + xlen + 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid bgzf block size"))
+    }
+    let mut block = Vec::with_capacity(bsize + 1);
+    block.extend_from_slice(&header);
+    block.extend_from_slice(&extra);
+    block.resize(bsize + 1, 0);
+    reader.read_exact(&mut block[12 + xlen..])?;
+    Ok(Some(block))
+}
+
+/// 解压一个bgzf block并检查CRC32
+fn inflate_bgzf_block(block: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+    let xlen = u16::from_le_bytes([block[10], block[11]]) as usize;
+    let (cdata, trailer) = block[12 + xlen..].split_at(block.len() - 12 - xlen - 8);
+    let crc = u32::from_le_bytes(trailer[..4].try_into().unwrap());
+    let isize = u32::from_le_bytes(trailer[4..].try_into().unwrap()) as usize;
+    let start = out.len();
+    out.reserve(isize);
+    DeflateDecoder::new(cdata).read_to_end(out)?;
+    if out.len() - start != isize || crc32fast::hash(&out[start..]) != crc {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bgzf block checksum mismatch"))
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::{write::DeflateEncoder, Compression as Level};
+    use ruzstd::encoding::{compress_to_vec, CompressionLevel};
+    use std::io::Write;
+
+    /// 测试数据，多行文本
+    fn sample_text(lines: usize) -> Vec<u8> {
+        (0..lines).map(|i| format!("chr{}\tsrc\texon\t{}\t{}\t.\t+\t.\tgene_id \"g{}\";\n", i % 25, i * 10, i * 10 + 100, i)).collect::<String>().into_bytes()
+    }
+
+    /// 把数据压缩为一个bgzf block
+    fn bgzf_block(data: &[u8]) -> Vec<u8> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Level::default());
+        encoder.write_all(data).unwrap();
+        let cdata = encoder.finish().unwrap();
+        let mut block = vec![0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0];
+        block.extend_from_slice(&((cdata.len() + 25) as u16).to_le_bytes());
+        block.extend_from_slice(&cdata);
+        block.extend_from_slice(&crc32fast::hash(data).to_le_bytes());
+        block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        block
+    }
+
+    #[test]
+    fn bgzf_matches_gzip_decoder() {
+        let text = sample_text(3000);
+        // 块数多于一批（threads * 16），中间夹一个空block，最后是标准的EOF block
+        let mut compressed: Vec<u8> = Vec::new();
+        for (i, chunk) in text.chunks(1000).enumerate() {
+            if i == 40 {
+                compressed.extend_from_slice(&bgzf_block(b""));
+            }
+            compressed.extend_from_slice(&bgzf_block(chunk));
+        }
+        let eof = [0x1f, 0x8b, 0x08, 0x04, 0, 0, 0, 0, 0, 0xff, 6, 0, b'B', b'C', 2, 0, 0x1b, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        compressed.extend_from_slice(&eof);
+        assert_eq!(Compression::detect(&compressed[..16]), Compression::Bgzf);
+
+        let mut expected = Vec::new();
+        MultiGzDecoder::new(&compressed[..]).read_to_end(&mut expected).unwrap();
+        assert_eq!(expected, text);
+        for threads in [2, 3] {
+            let mut out = Vec::new();
+            BgzfReader::new(&compressed[..], threads).read_to_end(&mut out).unwrap();
+            assert_eq!(out, expected, "threads = {}", threads);
+        }
+    }
+
+    #[test]
+    fn bgzf_rejects_corrupted_block() {
+        let mut compressed = bgzf_block(&sample_text(10));
+        let n = compressed.len();
+        compressed[n - 8] ^= 0xff;
+        let mut out = Vec::new();
+        assert!(BgzfReader::new(&compressed[..], 2).read_to_end(&mut out).is_err());
+    }
+
+    #[test]
+    fn zstd_reads_all_frames() {
+        let text = sample_text(3000);
+        let (first, second) = text.split_at(text.len() / 3);
+        // 两个frame中间夹一个skippable frame
+        let mut compressed = compress_to_vec(first, CompressionLevel::Fastest);
+        compressed.extend_from_slice(&0x184D2A50u32.to_le_bytes());
+        compressed.extend_from_slice(&3u32.to_le_bytes());
+        compressed.extend_from_slice(b"abc");
+        compressed.extend_from_slice(&compress_to_vec(second, CompressionLevel::Fastest));
+        let mut out = Vec::new();
+        MultiZstdDecoder::new(&compressed[..]).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, text);
+    }
+
+    #[test]
+    fn zstd_rejects_trailing_garbage() {
+        let mut compressed = compress_to_vec(&sample_text(10)[..], CompressionLevel::Fastest);
+        compressed.extend_from_slice(b"not a zstd frame");
+        let mut out = Vec::new();
+        assert!(MultiZstdDecoder::new(&compressed[..]).unwrap().read_to_end(&mut out).is_err());
     }
 }
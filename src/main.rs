@@ -1,7 +1,9 @@
 use infer_experiment::{
-    parse_paras::parse_para,
+    parse_paras::{parse_para, Command},
     error::MyError,
     infer::run_infer,
+    cache::run_index,
+    counts::run_merge,
 };
 
 fn main() {
@@ -12,8 +14,12 @@ fn main() {
 
 fn run() -> Result<(), MyError> {
     // 解析参数
-    let paras = parse_para()?;
-
-    // 开始统计
-    run_infer(&paras.input_file, paras.refgene, paras.gtf, &paras.feature, paras.sample_size, paras.mapq)
+    match parse_para()? {
+        // 开始统计
+        Command::Infer(paras) => run_infer(&paras),
+        // 生成注释缓存
+        Command::Index(paras) => run_index(&paras),
+        // 合并多个统计结果
+        Command::Merge(paras) => run_merge(&paras),
+    }
 }